@@ -0,0 +1,273 @@
+//! Memory bus of the VM: RAM from address 0, and devices mapped at higher
+//! addresses. An access must fall entirely within RAM or within one device.
+//! In user mode, RAM is limited to the window set through the MMU, and
+//! privileged devices are out of reach.
+
+use crate::{trap::Fault, uvm, vm::RAM_LEN};
+use std::{cell::Cell, rc::Rc};
+
+pub const REOM: &str = "READ OUT OF MEMORY";
+pub const WEOM: &str = "WRITE OUT OF MEMORY";
+
+/// Peripheral mapped on the bus
+pub trait Device {
+    /// Name shown in the device map
+    fn name(&self) -> &str;
+
+    /// Size of the address range taken by the device
+    fn len(&self) -> uvm;
+
+    /// Fills `buf` with the bytes from `offset` within the device
+    fn read(&mut self, offset: uvm, buf: &mut [u8]) -> Result<(), String>;
+
+    /// Writes `bytes` from `offset` within the device
+    fn write(&mut self, offset: uvm, bytes: &[u8]) -> Result<(), String>;
+
+    /// Only accessible in supervisor mode
+    fn privileged(&self) -> bool {
+        false
+    }
+
+    /// Called after each executed instruction, returns the interrupt line to
+    /// raise, if any
+    fn tick(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// State to put in snapshots
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Length of every state returned by `save`
+    fn state_len(&self) -> usize {
+        0
+    }
+
+    /// Replaces the state by one returned by `save`, of the checked length
+    fn restore(&mut self, _state: &[u8]) {}
+}
+
+/// Entry of the device map
+pub struct Region {
+    pub start: uvm,
+    pub len: uvm,
+    pub name: String,
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub privileged: bool,
+}
+
+/// RAM accessible in user mode, as a start address and a length
+pub type Window = Rc<Cell<(uvm, uvm)>>;
+
+struct Mapping {
+    start: uvm,
+    device: Box<dyn Device>,
+}
+
+impl Mapping {
+    /// Offset of `[addr, addr + len)` within the device, if it fits
+    fn offset(&self, addr: uvm, len: usize) -> Option<uvm> {
+        let offset = addr.checked_sub(self.start)?;
+        (offset.checked_add(len as uvm)? <= self.device.len()).then_some(offset)
+    }
+}
+
+pub struct Bus {
+    ram: [u8; RAM_LEN],
+    devices: Vec<Mapping>,
+    /// Accesses are checked for user mode
+    user: bool,
+    window: Window,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        let mut ram = [0u8; RAM_LEN];
+        for b in &mut ram {
+            *b = rand::random();
+        }
+        Self {
+            ram,
+            devices: Vec::new(),
+            user: false,
+            window: Rc::new(Cell::new((0, RAM_LEN as uvm))),
+        }
+    }
+
+    /// Window shared with the MMU device
+    pub fn window(&self) -> Window {
+        Rc::clone(&self.window)
+    }
+
+    pub fn set_user(&mut self, user: bool) {
+        self.user = user;
+    }
+
+    /// Whether user mode may access `[addr, addr + len)` of RAM
+    pub fn in_window(&self, addr: uvm, len: usize) -> bool {
+        let (start, size) = self.window.get();
+        addr.checked_sub(start)
+            .and_then(|offset| offset.checked_add(len as uvm))
+            .is_some_and(|end| end <= size)
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    /// Maps a device from `start`, which must not overlap RAM or another device
+    pub fn map(&mut self, start: uvm, device: Box<dyn Device>) -> Result<(), String> {
+        let end = start
+            .checked_add(device.len())
+            .ok_or_else(|| format!("{} does not fit at 0x{start:X}", device.name()))?;
+        if let Some(region) = self
+            .regions()
+            .into_iter()
+            .find(|region| start < region.start + region.len && region.start < end)
+        {
+            return Err(format!(
+                "{} at 0x{start:X} overlaps {}",
+                device.name(),
+                region.name
+            ));
+        }
+        self.devices.push(Mapping { start, device });
+        self.devices.sort_by_key(|mapping| mapping.start);
+        Ok(())
+    }
+
+    /// RAM, then the devices by address
+    pub fn regions(&self) -> Vec<Region> {
+        let ram = Region {
+            start: 0,
+            len: RAM_LEN as uvm,
+            name: "RAM".to_string(),
+            privileged: false,
+        };
+        let devices = self.devices.iter().map(|mapping| Region {
+            start: mapping.start,
+            len: mapping.device.len(),
+            name: mapping.device.name().to_string(),
+            privileged: mapping.device.privileged(),
+        });
+        std::iter::once(ram).chain(devices).collect()
+    }
+
+    pub fn read(&mut self, addr: uvm, buf: &mut [u8]) -> Result<(), Fault> {
+        let in_window = self.in_window(addr, buf.len());
+        if let Some(bytes) = self
+            .ram
+            .get(addr as usize..(addr as usize).saturating_add(buf.len()))
+        {
+            if self.user && !in_window {
+                return Err(Fault::PrivilegedAccess(addr));
+            }
+            buf.copy_from_slice(bytes);
+            return Ok(());
+        }
+        let user = self.user;
+        let (device, offset) = self
+            .device(addr, buf.len())
+            .ok_or(Fault::ReadOutOfMemory(addr))?;
+        if user && device.privileged() {
+            return Err(Fault::PrivilegedAccess(addr));
+        }
+        device.read(offset, buf).map_err(|reason| Fault::Device {
+            addr,
+            write: false,
+            reason,
+        })
+    }
+
+    pub fn write(&mut self, addr: uvm, bytes: &[u8]) -> Result<(), Fault> {
+        let in_window = self.in_window(addr, bytes.len());
+        if let Some(ram) = self
+            .ram
+            .get_mut(addr as usize..(addr as usize).saturating_add(bytes.len()))
+        {
+            if self.user && !in_window {
+                return Err(Fault::PrivilegedAccess(addr));
+            }
+            ram.copy_from_slice(bytes);
+            return Ok(());
+        }
+        let user = self.user;
+        let (device, offset) = self
+            .device(addr, bytes.len())
+            .ok_or(Fault::WriteOutOfMemory(addr))?;
+        if user && device.privileged() {
+            return Err(Fault::PrivilegedAccess(addr));
+        }
+        device.write(offset, bytes).map_err(|reason| Fault::Device {
+            addr,
+            write: true,
+            reason,
+        })
+    }
+
+    /// Ticks every device, returning the interrupt lines raised as a mask
+    pub fn tick(&mut self) -> u64 {
+        let mut lines = 0;
+        for mapping in &mut self.devices {
+            if let Some(line) = mapping.device.tick() {
+                lines |= 1u64.checked_shl(line.into()).unwrap_or_default();
+            }
+        }
+        lines
+    }
+
+    /// Name and state of each device, by address
+    pub fn save(&self) -> Vec<(String, Vec<u8>)> {
+        self.devices
+            .iter()
+            .map(|mapping| (mapping.device.name().to_string(), mapping.device.save()))
+            .collect()
+    }
+
+    /// Restores states returned by `save`, for the same devices. All states
+    /// are checked first, so that a failure leaves every device unchanged.
+    pub fn restore(&mut self, states: &[(String, Vec<u8>)]) -> Result<(), String> {
+        self.check(states)?;
+        for (mapping, (_, state)) in self.devices.iter_mut().zip(states) {
+            mapping.device.restore(state);
+        }
+        Ok(())
+    }
+
+    /// Checks that `states` are those of the mapped devices
+    fn check(&self, states: &[(String, Vec<u8>)]) -> Result<(), String> {
+        if states.len() != self.devices.len() {
+            return Err(format!(
+                "{} devices, expected {}",
+                states.len(),
+                self.devices.len()
+            ));
+        }
+        for (mapping, (name, state)) in self.devices.iter().zip(states) {
+            let (expected, len) = (mapping.device.name(), mapping.device.state_len());
+            if name != expected {
+                return Err(format!("device {name}, expected {expected}"));
+            }
+            if state.len() != len {
+                return Err(format!(
+                    "{name} : {} bytes of state, expected {len}",
+                    state.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Device holding `[addr, addr + len)`, and the offset within it
+    fn device(&mut self, addr: uvm, len: usize) -> Option<(&mut (dyn Device + 'static), uvm)> {
+        self.devices.iter_mut().find_map(|mapping| {
+            let offset = mapping.offset(addr, len)?;
+            Some((mapping.device.as_mut(), offset))
+        })
+    }
+}
@@ -0,0 +1,132 @@
+//! Core dump written when a program faults outside the debugger, for
+//! post-mortem inspection with `vm debug --core FILE`.
+//!
+//! The file is binary, with the same conventions as snapshots:
+//!
+//! ```text
+//! magic        "VMCORE"
+//! version      u16
+//! snapshot     length, then the machine state at the fault (see `snapshot.rs`)
+//! program      length of the loaded program
+//! fault        length, then the error message
+//! instruction  length, then the faulting instruction as text
+//! trace        count, then the last trace lines as length and text, oldest first
+//! ```
+
+use crate::snapshot::{self, Reader, Snapshot};
+use std::{fs, io, path::Path};
+
+const MAGIC: &[u8] = b"VMCORE";
+const VERSION: u16 = 1;
+
+pub struct CoreDump {
+    pub snapshot: Snapshot,
+    pub program_len: u64,
+    pub fault: String,
+    pub instruction: String,
+    pub trace: Vec<String>,
+}
+
+impl CoreDump {
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::decode(&fs::read(path)?).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid core dump : {err}"),
+            )
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.encode())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        snapshot::push_section(&mut bytes, &self.snapshot.encode());
+        bytes.extend(self.program_len.to_le_bytes());
+        snapshot::push_section(&mut bytes, self.fault.as_bytes());
+        snapshot::push_section(&mut bytes, self.instruction.as_bytes());
+        bytes.extend((self.trace.len() as u64).to_le_bytes());
+        for line in &self.trace {
+            snapshot::push_section(&mut bytes, line.as_bytes());
+        }
+        bytes
+    }
+
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader::new(bytes, MAGIC, VERSION)?;
+        let snapshot = Snapshot::decode(reader.section()?)?;
+        let program_len = reader.u64()?;
+        let fault = reader.string()?;
+        let instruction = reader.string()?;
+        let trace = (0..reader.u64()?)
+            .map(|_| reader.string())
+            .collect::<Result<_, _>>()?;
+        reader.finish()?;
+        Ok(Self {
+            snapshot,
+            program_len,
+            fault,
+            instruction,
+            trace,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    fn core() -> Vec<u8> {
+        CoreDump {
+            snapshot: VM::new().snapshot(),
+            program_len: 3,
+            fault: "DIVISION BY ZERO".to_string(),
+            instruction: "DIV    R0 0".to_string(),
+            trace: vec!["0000 : DIV    R0 0".to_string()],
+        }
+        .encode()
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = core();
+        let core = CoreDump::decode(&bytes).unwrap();
+        assert_eq!(core.program_len, 3);
+        assert_eq!(core.fault, "DIVISION BY ZERO");
+        assert_eq!(core.instruction, "DIV    R0 0");
+        assert_eq!(core.trace, ["0000 : DIV    R0 0"]);
+        assert_eq!(core.encode(), bytes);
+    }
+
+    #[test]
+    fn header() {
+        let mut bytes = core();
+        bytes.splice(..MAGIC.len(), *b"VMSNAP");
+        assert_eq!(CoreDump::decode(&bytes).err().unwrap(), "wrong file type");
+
+        let mut bytes = core();
+        bytes.splice(MAGIC.len()..MAGIC.len() + 2, 2u16.to_le_bytes());
+        assert_eq!(
+            CoreDump::decode(&bytes).err().unwrap(),
+            "unsupported version 2, expected 1"
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = core();
+        for len in 0..bytes.len() {
+            assert!(CoreDump::decode(bytes.get(..len).unwrap()).is_err());
+        }
+
+        let mut bytes = core();
+        bytes.push(0);
+        assert_eq!(CoreDump::decode(&bytes).err().unwrap(), "trailing bytes");
+    }
+}
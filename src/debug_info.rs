@@ -0,0 +1,297 @@
+//! Debug information side table, emitted by the compiler as a sidecar file
+//! next to the executable (`output.bin` -> `output.dbg`).
+//!
+//! The format is line based, one record per line:
+//!
+//! ```text
+//! file  <id> <path>
+//! line  <start> <end> <file id> <line>
+//! func  <start> <end> <name>
+//! local <function> <bp offset> <name>
+//! ```
+//!
+//! Address ranges are half-open (`start..end`), numbers are decimal or `0x`
+//! prefixed hexadecimal, and BP offsets may be negative. Blank lines and lines
+//! starting with `#` are ignored. Source paths are relative to the sidecar.
+
+use crate::uvm;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct DebugInfo {
+    base_dir: PathBuf,
+    files: Vec<(uvm, String)>,
+    lines: Vec<LineEntry>,
+    functions: Vec<Function>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LineEntry {
+    pub start: uvm,
+    pub end: uvm,
+    pub file: uvm,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub start: uvm,
+    pub end: uvm,
+    pub locals: Vec<Local>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct Local {
+    pub name: String,
+    pub bp_offset: i64,
+}
+
+impl DebugInfo {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut info = Self::parse(&fs::read_to_string(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        info.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(info)
+    }
+
+    /// Loads the sidecar of `program` if there is one
+    pub fn find(program: &Path) -> io::Result<Option<Self>> {
+        let path = program.with_extension("dbg");
+        if path.exists() {
+            Self::load(&path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut info = Self::default();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            info.parse_record(line)
+                .map_err(|err| format!("Invalid debug info at line {} : {err}", idx + 1))?;
+        }
+        Ok(info)
+    }
+
+    fn parse_record(&mut self, record: &str) -> Result<(), String> {
+        let mut fields = record.split_whitespace();
+        let kind = fields.next().unwrap_or_default();
+        let mut next = || fields.next().ok_or("missing field".to_string());
+        match kind {
+            "file" => {
+                let id = parse_number(next()?)?;
+                let path = next()?.to_string();
+                self.files.push((id, path));
+            }
+            "line" => {
+                let start = parse_number(next()?)?;
+                let end = parse_number(next()?)?;
+                let file = parse_number(next()?)?;
+                let line = parse_number(next()?)? as usize;
+                self.lines.push(LineEntry {
+                    start,
+                    end,
+                    file,
+                    line,
+                });
+            }
+            "func" => {
+                let start = parse_number(next()?)?;
+                let end = parse_number(next()?)?;
+                let name = next()?.to_string();
+                self.functions.push(Function {
+                    name,
+                    start,
+                    end,
+                    locals: Vec::new(),
+                });
+            }
+            "local" => {
+                let function = next()?;
+                let bp_offset = parse_offset(next()?)?;
+                let name = next()?.to_string();
+                self.functions
+                    .iter_mut()
+                    .find(|f| f.name == function)
+                    .ok_or(format!("unknown function {function}"))?
+                    .locals
+                    .push(Local { name, bp_offset });
+            }
+            _ => return Err(format!("unknown record {kind:?}")),
+        }
+        Ok(())
+    }
+
+    pub fn line_at(&self, addr: uvm) -> Option<&LineEntry> {
+        self.lines
+            .iter()
+            .find(|entry| (entry.start..entry.end).contains(&addr))
+    }
+
+    /// First address of `line` in the source file at `path`
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn address_of(&self, path: &Path, line: usize) -> Option<uvm> {
+        let file = self
+            .files
+            .iter()
+            .find(|(_, name)| path.ends_with(name) || self.base_dir.join(name) == path)
+            .map(|(id, _)| *id)?;
+        self.lines
+            .iter()
+            .filter(|entry| entry.file == file && entry.line == line)
+            .map(|entry| entry.start)
+            .min()
+    }
+
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+
+    pub fn function_at(&self, addr: uvm) -> Option<&Function> {
+        self.functions
+            .iter()
+            .find(|function| (function.start..function.end).contains(&addr))
+    }
+
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
+    pub fn files(&self) -> impl Iterator<Item = (uvm, &str)> {
+        self.files.iter().map(|(id, path)| (*id, path.as_str()))
+    }
+
+    pub fn file_name(&self, file: uvm) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|(id, _)| *id == file)
+            .map(|(_, path)| path.as_str())
+    }
+
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn source_path(&self, file: uvm) -> Option<PathBuf> {
+        self.file_name(file).map(|path| self.base_dir.join(path))
+    }
+
+    /// Describes `addr` as `in function foo at main.c:42`, or whichever part of
+    /// it is known
+    pub fn symbolize(&self, addr: uvm) -> Option<String> {
+        let function = self.function_at(addr).map(|f| f.name.as_str());
+        let line = self.line_at(addr).map(|entry| {
+            let file = self.file_name(entry.file).unwrap_or("??");
+            format!("{file}:{}", entry.line)
+        });
+        match (function, line) {
+            (Some(function), Some(line)) => Some(format!("in function {function} at {line}")),
+            (Some(function), None) => Some(format!("in function {function}")),
+            (None, Some(line)) => Some(format!("at {line}")),
+            (None, None) => None,
+        }
+    }
+}
+
+fn parse_number(str: &str) -> Result<uvm, String> {
+    match str.strip_prefix("0x") {
+        Some(hex) => uvm::from_str_radix(hex, 16),
+        None => str.parse(),
+    }
+    .map_err(|_| format!("invalid number {str:?}"))
+}
+
+fn parse_offset(str: &str) -> Result<i64, String> {
+    let (negative, abs) = match str.strip_prefix('-') {
+        Some(abs) => (true, abs),
+        None => (false, str.strip_prefix('+').unwrap_or(str)),
+    };
+    let abs = i64::try_from(parse_number(abs)?).map_err(|_| format!("invalid offset {str:?}"))?;
+    Ok(if negative { -abs } else { abs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIDECAR: &str = "
+# comment
+file 0 main.c
+file 1 lib/util.c
+
+func 0x0 0x20 main
+func 32 0x40 helper
+local main -8 x
+local main +0x10 y
+line 0x0 0x10 0 3
+line 0x10 0x20 0 4
+line 0x20 0x40 1 10
+line 0x30 0x38 1 11
+";
+
+    #[test]
+    fn parse() {
+        let info = DebugInfo::parse(SIDECAR).unwrap();
+        assert_eq!(
+            info.files().collect::<Vec<_>>(),
+            [(0, "main.c"), (1, "lib/util.c")]
+        );
+        let main = info.function("main").unwrap();
+        assert_eq!((main.start, main.end), (0, 0x20));
+        let locals: Vec<_> = main
+            .locals
+            .iter()
+            .map(|local| (local.name.as_str(), local.bp_offset))
+            .collect();
+        assert_eq!(locals, [("x", -8), ("y", 16)]);
+        assert!(info.function("missing").is_none());
+    }
+
+    #[test]
+    fn lookups() {
+        let info = DebugInfo::parse(SIDECAR).unwrap();
+        assert_eq!(info.line_at(0x18).map(|entry| entry.line), Some(4));
+        assert!(info.line_at(0x40).is_none());
+        assert_eq!(info.function_at(0x20).unwrap().name, "helper");
+        assert!(info.function_at(0x40).is_none());
+
+        assert_eq!(info.address_of(Path::new("main.c"), 4), Some(0x10));
+        assert_eq!(info.address_of(Path::new("src/lib/util.c"), 10), Some(0x20));
+        assert_eq!(info.address_of(Path::new("main.c"), 5), None);
+        assert_eq!(info.address_of(Path::new("other.c"), 3), None);
+
+        assert_eq!(
+            info.symbolize(0x14).as_deref(),
+            Some("in function main at main.c:4")
+        );
+        assert_eq!(info.symbolize(0x100), None);
+    }
+
+    #[test]
+    fn duplicate_addresses() {
+        // The first record covering an address wins
+        let info = DebugInfo::parse(SIDECAR).unwrap();
+        assert_eq!(info.line_at(0x30).map(|entry| entry.line), Some(10));
+        assert_eq!(info.address_of(Path::new("lib/util.c"), 11), Some(0x30));
+    }
+
+    #[test]
+    fn malformed() {
+        for (text, err) in [
+            ("file 0", "line 1 : missing field"),
+            ("\nline 0 0x10 0 x", "line 2 : invalid number \"x\""),
+            ("func 0xZZ 0 main", "line 1 : invalid number \"0xZZ\""),
+            ("local main -8 x", "line 1 : unknown function main"),
+            ("symbol 0 main", "line 1 : unknown record \"symbol\""),
+        ] {
+            assert_eq!(
+                DebugInfo::parse(text).unwrap_err(),
+                format!("Invalid debug info at {err}")
+            );
+        }
+    }
+}
@@ -0,0 +1,622 @@
+//! Debugger commands, shared by the TUI prompt and scripts
+
+use super::session::{Session, Stop, WatchKind, READ_ONLY};
+use crate::{
+    registers::{Registers, REG_COUNT},
+    snapshot::Snapshot,
+    uvm, REG_LEN,
+};
+use std::{fmt::Write as _, path::Path};
+
+const COMMANDS: &[(&str, &str)] = &[
+    ("break", "break LOCATION        Sets a breakpoint"),
+    (
+        "delete",
+        "delete [LOCATION]     Deletes a breakpoint, or all of them",
+    ),
+    ("step", "step [N]              Executes N instructions"),
+    ("next", "next                  Steps over a source line"),
+    ("into", "into                  Steps into a source line"),
+    (
+        "finish",
+        "finish                Runs until the current function returns",
+    ),
+    (
+        "continue",
+        "continue              Runs until a breakpoint or exit",
+    ),
+    ("run", "run N                 Runs N instructions"),
+    (
+        "until",
+        "until LOCATION        Runs until PC reaches a location",
+    ),
+    ("set", "set reg REG = VALUE   Sets a register"),
+    (
+        "set",
+        "set mem ADDR = VALUE  Sets a 64 bit value in memory (byte: a single byte)",
+    ),
+    (
+        "x",
+        "x/NFU ADDRESS         Examines N units (b|h|w|g) of memory as x|d|u|c",
+    ),
+    (
+        "print",
+        "print EXPRESSION      Prints a register, local, *address or number",
+    ),
+    (
+        "assert",
+        "assert A ==|!= B      Fails unless both values compare as expected",
+    ),
+    ("goto", "goto LOCATION         Moves PC to a location"),
+    (
+        "view",
+        "view LOCATION         Shows a location in the Program and RAM panes",
+    ),
+    (
+        "find",
+        "find \"TEXT\"|VALUE     Searches memory for text or a 64 bit value",
+    ),
+    (
+        "find",
+        "find/b XX XX ...      Searches memory for a byte pattern",
+    ),
+    (
+        "info",
+        "info break|reg|map    Lists breakpoints, registers or the device map",
+    ),
+    (
+        "save",
+        "save FILE             Saves a snapshot of the machine to a file",
+    ),
+    (
+        "load",
+        "load FILE             Restores a snapshot of the machine from a file",
+    ),
+    ("help", "help                  Lists commands"),
+];
+
+const ALIASES: &[(&str, &str)] = &[
+    ("b", "break"),
+    ("d", "delete"),
+    ("s", "step"),
+    ("si", "step"),
+    ("n", "next"),
+    ("c", "continue"),
+    ("p", "print"),
+];
+
+/// Commands that run or modify the machine
+const MODIFYING: &[&str] = &[
+    "step", "next", "into", "finish", "continue", "run", "until", "set", "goto", "load",
+];
+
+/// Result of a command
+#[derive(Default)]
+pub struct Reply {
+    pub output: Vec<String>,
+    /// The program should keep running until it stops on its own
+    pub resume: bool,
+    /// Resuming stops after this many instructions
+    pub limit: Option<usize>,
+    /// Resuming stops when PC reaches this address
+    pub until: Option<uvm>,
+    /// Memory range the views should move to
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
+    pub view: Option<(uvm, usize)>,
+}
+
+impl Reply {
+    fn line(line: String) -> Self {
+        Self {
+            output: vec![line],
+            ..Self::default()
+        }
+    }
+}
+
+pub fn execute(session: &mut Session, line: &str) -> Result<Reply, String> {
+    let line = line.trim();
+    let (name, args) = line
+        .split_once(|c: char| c.is_whitespace() || c == '/')
+        .map_or((line, ""), |(name, _)| {
+            (name, line.get(name.len()..).unwrap_or_default().trim())
+        });
+    if name.is_empty() {
+        return Ok(Reply::default());
+    }
+
+    let command = resolve(name)?;
+    if session.is_read_only() && MODIFYING.contains(&command) {
+        return Err(READ_ONLY.to_string());
+    }
+    match command {
+        "break" => {
+            let addr = parse_location(session, args)?;
+            session.add_breakpoint(addr);
+            Ok(Reply::line(format!(
+                "Breakpoint at {}",
+                describe_addr(session, addr)
+            )))
+        }
+        "delete" if args.is_empty() => {
+            session.clear_breakpoints();
+            Ok(Reply::line("Deleted all breakpoints".to_string()))
+        }
+        "delete" => {
+            let addr = parse_location(session, args)?;
+            if session.remove_breakpoint(addr) {
+                Ok(Reply::line(format!("Deleted breakpoint at 0x{addr:X}")))
+            } else {
+                Err(format!("No breakpoint at 0x{addr:X}"))
+            }
+        }
+        "step" => {
+            let count = if args.is_empty() {
+                1
+            } else {
+                parse_number(args)? as usize
+            };
+            let stop = session.resume(count)?;
+            Ok(Reply::line(describe_stop(session, stop)))
+        }
+        "next" => source_step(session, Session::step_over),
+        "into" => source_step(session, Session::step_into),
+        "finish" => source_step(session, Session::step_out),
+        "continue" => Ok(Reply {
+            resume: true,
+            ..Reply::default()
+        }),
+        "run" => Ok(Reply {
+            resume: true,
+            limit: Some(parse_number(args)? as usize),
+            ..Reply::default()
+        }),
+        "until" => Ok(Reply {
+            resume: true,
+            until: Some(parse_location(session, args)?),
+            ..Reply::default()
+        }),
+        "set" => set(session, args),
+        "x" => examine(session, args),
+        "print" => {
+            let value = parse_value(session, args)?;
+            Ok(Reply::line(format!(
+                "{args} = 0x{value:X} ({value}, {})",
+                value.cast_signed()
+            )))
+        }
+        "assert" => assert(session, args),
+        "goto" => {
+            let addr = parse_location(session, args)?;
+            session.vm_mut().set_reg(0, addr)?;
+            Ok(Reply::line(format!(
+                "PC = {}",
+                describe_addr(session, addr)
+            )))
+        }
+        "save" => save(session, args),
+        "load" => load(session, args),
+        "view" => {
+            let addr = parse_location(session, args)?;
+            Ok(Reply {
+                output: vec![format!("Viewing {}", describe_addr(session, addr))],
+                view: Some((addr, 1)),
+                ..Reply::default()
+            })
+        }
+        "find" => find(session, args),
+        "info" => info(session, args),
+        _ => Ok(Reply {
+            output: COMMANDS
+                .iter()
+                .map(|(_, help)| (*help).to_string())
+                .collect(),
+            ..Reply::default()
+        }),
+    }
+}
+
+/// Completions of a partial command line
+#[cfg_attr(not(feature = "tui"), allow(unused))]
+pub fn complete(line: &str) -> Vec<String> {
+    let words = line.split(' ').collect::<Vec<_>>();
+    let (candidates, last): (Vec<String>, &str) = match words.as_slice() {
+        [word] => {
+            let mut names = COMMANDS
+                .iter()
+                .map(|(name, _)| (*name).to_string())
+                .collect::<Vec<_>>();
+            // Commands with several forms have several help rows
+            names.dedup();
+            (names, word)
+        }
+        ["set", word] => (["reg", "mem", "byte"].map(str::to_string).to_vec(), word),
+        ["set", "reg", word] | ["print" | "p", word] => {
+            ((0..REG_COUNT).map(Registers::register_name).collect(), word)
+        }
+        ["info", word] => (["break", "reg", "map"].map(str::to_string).to_vec(), word),
+        _ => return Vec::new(),
+    };
+    let prefix = line.get(..line.len() - last.len()).unwrap_or_default();
+    candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(last))
+        .map(|candidate| format!("{prefix}{candidate} "))
+        .collect()
+}
+
+pub fn describe_stop(session: &Session, stop: Stop) -> String {
+    match stop {
+        Stop::Done => format!("Stopped at {}", describe_addr(session, session.vm().pc())),
+        Stop::Exited(exit_code) => format!("Program exited with code : {exit_code}"),
+        Stop::Breakpoint(addr) => format!("Breakpoint at {}", describe_addr(session, addr)),
+        Stop::Watchpoint(watchpoint) => {
+            let kind = match watchpoint.kind {
+                WatchKind::Write => "Write",
+                WatchKind::Read => "Read",
+                WatchKind::Access => "Access",
+            };
+            format!(
+                "{kind} watchpoint 0x{:X} at {}",
+                watchpoint.addr,
+                describe_addr(session, session.vm().pc())
+            )
+        }
+    }
+}
+
+fn describe_addr(session: &Session, addr: uvm) -> String {
+    match session.debug_info().and_then(|info| info.symbolize(addr)) {
+        Some(location) => format!("0x{addr:X} {location}"),
+        None => format!("0x{addr:X}"),
+    }
+}
+
+fn resolve(name: &str) -> Result<&'static str, String> {
+    if let Some((_, command)) = ALIASES.iter().find(|(alias, _)| *alias == name) {
+        return Ok(command);
+    }
+    let mut matches = COMMANDS
+        .iter()
+        .map(|(command, _)| *command)
+        .filter(|command| command.starts_with(name));
+    match (matches.next(), matches.next()) {
+        (Some(command), None) => Ok(command),
+        (Some(command), Some(other)) if command == other => Ok(command),
+        (Some(_), Some(_)) => Err(format!("Ambiguous command {name:?}")),
+        (None, _) => Err(format!("Unknown command {name:?}, try help")),
+    }
+}
+
+fn source_step(
+    session: &mut Session,
+    run: fn(&mut Session) -> Result<Option<uvm>, String>,
+) -> Result<Reply, String> {
+    let stop = match run(session)? {
+        Some(exit_code) => Stop::Exited(exit_code),
+        None => Stop::Done,
+    };
+    Ok(Reply::line(describe_stop(session, stop)))
+}
+
+fn set(session: &mut Session, args: &str) -> Result<Reply, String> {
+    const USAGE: &str = "Usage : set reg|mem|byte TARGET = VALUE";
+    let (kind, args) = args.split_once(' ').ok_or(USAGE)?;
+    let (target, value) = args
+        .split_once('=')
+        .or_else(|| args.trim().split_once(' '))
+        .ok_or(USAGE)?;
+    let (target, value) = (target.trim(), parse_value(session, value.trim())?);
+    match kind {
+        "reg" => {
+            let idx =
+                Registers::register_index(target).ok_or(format!("Unknown register {target:?}"))?;
+            session.vm_mut().set_reg(idx, value)?;
+            Ok(Reply::line(format!(
+                "{} = 0x{value:X}",
+                Registers::register_name(idx)
+            )))
+        }
+        "mem" | "byte" => {
+            let addr = parse_value(session, target)?;
+            let bytes = value.to_le_bytes();
+            let len = if kind == "byte" { 1 } else { REG_LEN };
+            session
+                .vm_mut()
+                .write_ram(addr, bytes.get(..len).unwrap_or_default())?;
+            Ok(Reply::line(format!("@0x{addr:X} = 0x{value:X}")))
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn examine(session: &Session, args: &str) -> Result<Reply, String> {
+    let (format, addr) = match args.strip_prefix('/') {
+        Some(args) => args.split_once(' ').unwrap_or((args, "")),
+        None => ("", args),
+    };
+    let addr = parse_value(session, addr.trim())?;
+
+    let digits = format.trim_end_matches(|c: char| !c.is_ascii_digit());
+    let count = if digits.is_empty() {
+        16
+    } else {
+        parse_number(digits)? as usize
+    };
+    let (mut kind, mut unit) = ('x', 1);
+    for c in format.get(digits.len()..).unwrap_or_default().chars() {
+        match c {
+            'x' | 'd' | 'u' | 'c' => kind = c,
+            'b' => unit = 1,
+            'h' => unit = 2,
+            'w' => unit = 4,
+            'g' => unit = 8,
+            _ => return Err(format!("Invalid format {c:?}")),
+        }
+    }
+
+    let len = count
+        .checked_mul(unit)
+        .ok_or_else(|| format!("Too many units : {count}"))?;
+    let bytes = session.vm().read_ram(addr, len)?;
+    let output = bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(row, bytes)| {
+            let mut line = format!("0x{:08X}:", addr as usize + row * 16);
+            for chunk in bytes.chunks(unit) {
+                let mut value = [0; REG_LEN];
+                value
+                    .get_mut(..chunk.len())
+                    .unwrap_or_default()
+                    .copy_from_slice(chunk);
+                let value = uvm::from_le_bytes(value);
+                let shift = uvm::BITS as usize - unit * 8;
+                let _ = match kind {
+                    'd' => write!(line, " {}", (value << shift).cast_signed() >> shift),
+                    'u' => write!(line, " {value}"),
+                    'c' if unit == 1 => write!(line, " {:?}", char::from(value as u8)),
+                    _ => write!(line, " {value:0width$X}", width = unit * 2),
+                };
+            }
+            line
+        })
+        .collect();
+    Ok(Reply {
+        output,
+        ..Reply::default()
+    })
+}
+
+fn save(session: &Session, args: &str) -> Result<Reply, String> {
+    let path = parse_path(args)?;
+    session
+        .vm()
+        .snapshot()
+        .save(path)
+        .map_err(|err| format!("Cannot save {} : {err}", path.display()))?;
+    Ok(Reply::line(format!(
+        "Saved snapshot at cycle {} to {}",
+        session.vm().cycles(),
+        path.display()
+    )))
+}
+
+fn load(session: &mut Session, args: &str) -> Result<Reply, String> {
+    let path = parse_path(args)?;
+    let snapshot =
+        Snapshot::load(path).map_err(|err| format!("Cannot load {} : {err}", path.display()))?;
+    session.restore(&snapshot)?;
+    Ok(Reply::line(format!(
+        "Restored snapshot at cycle {}, PC = {}",
+        snapshot.cycles,
+        describe_addr(session, session.vm().pc())
+    )))
+}
+
+/// Matches listed by `find`
+const FIND_LIMIT: usize = 16;
+
+fn find(session: &Session, args: &str) -> Result<Reply, String> {
+    let pattern = if let Some(bytes) = args.strip_prefix("/b") {
+        bytes
+            .split_whitespace()
+            .map(|byte| u8::from_str_radix(byte.trim_start_matches("0x"), 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("Invalid byte pattern {bytes:?}"))?
+    } else if let Some(text) = args.strip_prefix('"') {
+        text.strip_suffix('"')
+            .ok_or("Missing closing quote")?
+            .as_bytes()
+            .to_vec()
+    } else {
+        parse_value(session, args)?.to_le_bytes().to_vec()
+    };
+    if pattern.is_empty() {
+        return Err("Empty pattern".to_string());
+    }
+
+    let ram = session.vm().ram();
+    let matches = ram
+        .windows(pattern.len())
+        .enumerate()
+        .filter(|(_, window)| *window == pattern.as_slice())
+        .map(|(addr, _)| addr as uvm)
+        .collect::<Vec<_>>();
+    let Some(first) = matches.first() else {
+        return Err("Pattern not found".to_string());
+    };
+
+    let mut output = matches
+        .iter()
+        .take(FIND_LIMIT)
+        .map(|addr| format!("Found at {}", describe_addr(session, *addr)))
+        .collect::<Vec<_>>();
+    if matches.len() > FIND_LIMIT {
+        output.push(format!("... {} more", matches.len() - FIND_LIMIT));
+    }
+    Ok(Reply {
+        output,
+        view: Some((*first, pattern.len())),
+        ..Reply::default()
+    })
+}
+
+fn info(session: &Session, args: &str) -> Result<Reply, String> {
+    let output = match args {
+        "break" | "b" => session
+            .breakpoints()
+            .map(|addr| describe_addr(session, addr))
+            .collect(),
+        "reg" | "r" => (0..REG_COUNT)
+            .map(|idx| {
+                let value = session.vm().get_reg(idx)?;
+                Ok(format!(
+                    "{} 0x{value:016X} {value}",
+                    Registers::register_name(idx)
+                ))
+            })
+            .collect::<Result<_, String>>()?,
+        "map" | "m" => session
+            .vm()
+            .device_map()
+            .into_iter()
+            .map(|region| {
+                let end = region.start + region.len - 1;
+                let mode = if region.privileged {
+                    " (supervisor)"
+                } else {
+                    ""
+                };
+                format!("0x{:08X}-0x{end:08X} {}{mode}", region.start, region.name)
+            })
+            .collect(),
+        _ => return Err("Usage : info break|reg|map".to_string()),
+    };
+    Ok(Reply {
+        output,
+        ..Reply::default()
+    })
+}
+
+fn parse_number(str: &str) -> Result<uvm, String> {
+    let (negative, abs) = match str.strip_prefix('-') {
+        Some(abs) => (true, abs),
+        None => (false, str),
+    };
+    let value = match abs.strip_prefix("0x") {
+        Some(hex) => uvm::from_str_radix(hex, 16),
+        None => abs.parse(),
+    }
+    .map_err(|_| format!("Invalid number {str:?}"))?;
+    Ok(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+fn assert(session: &Session, args: &str) -> Result<Reply, String> {
+    let (lhs, op, rhs) = ["==", "!="]
+        .into_iter()
+        .find_map(|op| {
+            args.split_once(op)
+                .map(|(lhs, rhs)| (lhs.trim(), op, rhs.trim()))
+        })
+        .ok_or("Expected EXPR == EXPR or EXPR != EXPR")?;
+    let (left, right) = (parse_value(session, lhs)?, parse_value(session, rhs)?);
+    if (left == right) == (op == "==") {
+        Ok(Reply::line(format!("Passed: {args}")))
+    } else {
+        Err(format!(
+            "Assertion failed: {lhs} = 0x{left:X}, expected {op} 0x{right:X}"
+        ))
+    }
+}
+
+/// Parses a number, a register, a local variable or `*ADDRESS`
+fn parse_value(session: &Session, str: &str) -> Result<uvm, String> {
+    let vm = session.vm();
+    if str.is_empty() {
+        return Err("Missing expression".to_string());
+    }
+    if let Some(addr) = str.strip_prefix('*') {
+        let bytes = vm.read_ram(parse_value(session, addr)?, REG_LEN)?;
+        return Ok(uvm::from_le_bytes(
+            bytes.try_into().map_err(|_| "Invalid read")?,
+        ));
+    }
+    if let Some(idx) = Registers::register_index(str) {
+        return vm.get_reg(idx);
+    }
+    if let Some(local) = session
+        .debug_info()
+        .and_then(|info| info.function_at(vm.pc()))
+        .and_then(|function| function.locals.iter().find(|local| local.name == str))
+    {
+        let addr = vm.bp().wrapping_add_signed(local.bp_offset);
+        let bytes = vm.read_ram(addr, REG_LEN)?;
+        return Ok(uvm::from_le_bytes(
+            bytes.try_into().map_err(|_| "Invalid read")?,
+        ));
+    }
+    parse_number(str)
+}
+
+/// Parses the file name argument of `save` and `load`
+fn parse_path(str: &str) -> Result<&Path, String> {
+    if str.is_empty() {
+        Err("Missing file name".to_string())
+    } else {
+        Ok(Path::new(str))
+    }
+}
+
+/// Parses `*ADDRESS`, `0xADDRESS`, `FILE:LINE`, `LINE` or `FUNCTION`
+fn parse_location(session: &Session, str: &str) -> Result<uvm, String> {
+    if let Some(addr) = str.strip_prefix('*') {
+        return parse_value(session, addr);
+    }
+    if str.starts_with("0x") {
+        return parse_number(str);
+    }
+    let Some(info) = session.debug_info() else {
+        return parse_number(str);
+    };
+    let (file, line) = match str.rsplit_once(':') {
+        Some((file, line)) => (Some(file), line),
+        None => (None, str),
+    };
+    if let Ok(line) = line.parse::<usize>() {
+        let file = match file {
+            Some(file) => file.to_string(),
+            None => session
+                .line()
+                .and_then(|(file, _)| info.file_name(file))
+                .ok_or("No current source file")?
+                .to_string(),
+        };
+        return info
+            .address_of(Path::new(&file), line)
+            .ok_or(format!("No code at {file}:{line}"));
+    }
+    info.function(str)
+        .map(|function| function.start)
+        .ok_or(format!("Unknown location {str:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_commands() {
+        assert_eq!(complete("s"), ["step ", "set ", "save "]);
+        assert_eq!(complete("f"), ["finish ", "find "]);
+        assert_eq!(complete("set "), ["set reg ", "set mem ", "set byte "]);
+        assert_eq!(complete("set m"), ["set mem "]);
+        assert_eq!(complete("set reg S"), ["set reg SP ", "set reg SR "]);
+        assert_eq!(complete("info m"), ["info map "]);
+        assert!(complete("step 1 2").is_empty());
+    }
+}
@@ -0,0 +1,579 @@
+//! Debug Adapter Protocol server over stdio, see
+//! <https://microsoft.github.io/debug-adapter-protocol/specification>
+
+use super::session::{Session, Stop};
+use crate::{
+    debug_info::DebugInfo,
+    registers::{Registers, REG_COUNT},
+    uvm,
+    vm::Stream,
+    REG_LEN,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+/// Instructions executed between two checks for a pause request
+const BATCH_LEN: usize = 4096;
+const THREAD_ID: u64 = 1;
+const REGISTERS_REF: u64 = 1;
+/// Variables reference of the locals of frame 0, frame N uses `LOCALS_REF + N`
+const LOCALS_REF: u64 = 2;
+
+pub fn serve() -> io::Result<()> {
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        while let Ok(Some(message)) = read_message(&mut stdin) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    Adapter {
+        session: None,
+        requests,
+        pending: VecDeque::new(),
+        seq: 0,
+        stop_on_entry: false,
+        source_breakpoints: HashMap::new(),
+        instruction_breakpoints: Vec::new(),
+    }
+    .run()
+}
+
+struct Adapter {
+    session: Option<Session>,
+    requests: Receiver<Value>,
+    /// Requests received while the program was running
+    pending: VecDeque<Value>,
+    seq: u64,
+    stop_on_entry: bool,
+    source_breakpoints: HashMap<PathBuf, Vec<uvm>>,
+    instruction_breakpoints: Vec<uvm>,
+}
+
+enum Resume {
+    Continue,
+    StepOver,
+    StepInto,
+    StepOut,
+    Instruction,
+}
+
+// Indexing a `Value` returns `Null` instead of panicking
+#[allow(clippy::indexing_slicing)]
+impl Adapter {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(request) = self
+            .pending
+            .pop_front()
+            .or_else(|| self.requests.recv().ok())
+        {
+            if !self.handle(&request)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles one request, returns `false` once the client disconnected
+    fn handle(&mut self, request: &Value) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let instruction = args["granularity"] == "instruction";
+        let resume = match command {
+            "continue" => Some(Resume::Continue),
+            "next" | "stepIn" if instruction => Some(Resume::Instruction),
+            "next" => Some(Resume::StepOver),
+            "stepIn" => Some(Resume::StepInto),
+            "stepOut" => Some(Resume::StepOut),
+            _ => None,
+        };
+        if let Some(resume) = resume {
+            let result = self
+                .session()
+                .map(|_| json!({ "allThreadsContinued": true }));
+            let running = result.is_ok();
+            self.respond(request, result)?;
+            if running {
+                self.resume(&resume)?;
+            }
+            return Ok(true);
+        }
+
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsSteppingGranularity": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" | "pause" | "configurationDone" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => self.scopes(args),
+            "variables" => self.variables(args),
+            "readMemory" => self.read_memory(args),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                return Ok(false);
+            }
+            _ => Err(format!("Unsupported request {command}")),
+        };
+        let launched = command == "launch" && result.is_ok();
+        self.respond(request, result)?;
+
+        if launched {
+            self.event("initialized", json!({}))?;
+        }
+        if command == "configurationDone" && self.session.is_some() {
+            if self.stop_on_entry {
+                self.stopped("entry", None)?;
+            } else {
+                self.resume(&Resume::Continue)?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn session(&self) -> Result<&Session, String> {
+        self.session
+            .as_ref()
+            .ok_or("No program launched".to_string())
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = PathBuf::from(args["program"].as_str().ok_or("Missing program path")?);
+        let program = fs::read(&path).map_err(|err| format!("{}: {err}", path.display()))?;
+        let debug_info = match args["debugInfo"].as_str() {
+            Some(debug_info) => DebugInfo::load(Path::new(debug_info)).map(Some),
+            None => DebugInfo::find(&path),
+        }
+        .map_err(|err| err.to_string())?;
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or_default();
+        self.session = Some(Session::new(&program, debug_info.map(Rc::new), None));
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let session = self.session.as_mut().ok_or("No program launched")?;
+        let path = PathBuf::from(args["source"]["path"].as_str().unwrap_or_default());
+        for addr in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            session.remove_breakpoint(addr);
+        }
+
+        let mut addresses = Vec::new();
+        let breakpoints = args["breakpoints"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .map(|breakpoint| {
+                let line = breakpoint["line"].as_u64().unwrap_or_default();
+                let addr = session
+                    .debug_info()
+                    .and_then(|info| info.address_of(&path, line as usize));
+                if let Some(addr) = addr {
+                    session.add_breakpoint(addr);
+                    addresses.push(addr);
+                }
+                json!({
+                    "verified": addr.is_some(),
+                    "line": line,
+                    "instructionReference": addr.map(|addr| format!("0x{addr:X}")),
+                })
+            })
+            .collect::<Vec<_>>();
+        self.source_breakpoints.insert(path, addresses);
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let session = self.session.as_mut().ok_or("No program launched")?;
+        for addr in self.instruction_breakpoints.drain(..) {
+            session.remove_breakpoint(addr);
+        }
+
+        let breakpoints = args["breakpoints"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .map(|breakpoint| {
+                let addr = parse_reference(&breakpoint["instructionReference"]).map(|addr| {
+                    addr.wrapping_add_signed(breakpoint["offset"].as_i64().unwrap_or_default())
+                });
+                if let Some(addr) = addr {
+                    session.add_breakpoint(addr);
+                    self.instruction_breakpoints.push(addr);
+                }
+                json!({ "verified": addr.is_some() })
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&self) -> Result<Value, String> {
+        let session = self.session()?;
+        let info = session.debug_info();
+        let frames = session
+            .backtrace()
+            .into_iter()
+            .enumerate()
+            .map(|(id, (addr, _, entry))| {
+                let name = info
+                    .and_then(|info| info.function_at(addr))
+                    .map_or_else(|| format!("0x{entry:X}"), |function| function.name.clone());
+                let line = info.and_then(|info| info.line_at(addr));
+                let source = line
+                    .and_then(|entry| info?.source_path(entry.file))
+                    .map(|path| json!({ "path": path }));
+                json!({
+                    "id": id,
+                    "name": name,
+                    "source": source,
+                    "line": line.map_or(0, |entry| entry.line),
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{addr:X}"),
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    fn scopes(&self, args: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let id = args["frameId"].as_u64().unwrap_or_default();
+        let mut scopes = vec![json!({
+            "name": "Registers",
+            "variablesReference": REGISTERS_REF,
+            "expensive": false,
+        })];
+        if let Some((addr, ..)) = session.backtrace().get(id as usize) {
+            if session
+                .debug_info()
+                .and_then(|info| info.function_at(*addr))
+                .is_some_and(|function| !function.locals.is_empty())
+            {
+                scopes.push(json!({
+                    "name": "Locals",
+                    "variablesReference": LOCALS_REF + id,
+                    "expensive": false,
+                }));
+            }
+        }
+        Ok(json!({ "scopes": scopes }))
+    }
+
+    fn variables(&self, args: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let vm = session.vm();
+        let reference = args["variablesReference"].as_u64().unwrap_or_default();
+        let variables = if reference == REGISTERS_REF {
+            (0..REG_COUNT)
+                .map(|idx| {
+                    let value = vm.get_reg(idx)?;
+                    Ok(json!({
+                        "name": Registers::register_name(idx),
+                        "value": format!("0x{value:016X}"),
+                        "variablesReference": 0,
+                        "memoryReference": format!("0x{value:X}"),
+                    }))
+                })
+                .collect::<Result<Vec<_>, String>>()?
+        } else {
+            let frames = session.backtrace();
+            let (addr, bp, _) = reference
+                .checked_sub(LOCALS_REF)
+                .and_then(|id| frames.get(id as usize))
+                .ok_or("Invalid variables reference")?;
+            session
+                .debug_info()
+                .and_then(|info| info.function_at(*addr))
+                .map(|function| function.locals.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .map(|local| {
+                    let addr = bp.wrapping_add_signed(local.bp_offset);
+                    let value = vm
+                        .read_ram(addr, REG_LEN)
+                        .ok()
+                        .and_then(|bytes| bytes.try_into().ok())
+                        .map_or_else(
+                            || "???".to_string(),
+                            |bytes| uvm::from_le_bytes(bytes).to_string(),
+                        );
+                    json!({
+                        "name": local.name,
+                        "value": value,
+                        "variablesReference": 0,
+                        "memoryReference": format!("0x{addr:X}"),
+                    })
+                })
+                .collect()
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let vm = self.session()?.vm();
+        let addr = parse_reference(&args["memoryReference"])
+            .ok_or("Invalid memory reference")?
+            .wrapping_add_signed(args["offset"].as_i64().unwrap_or_default());
+        let count = args["count"].as_u64().unwrap_or_default() as usize;
+        let readable = (0..count)
+            .take_while(|offset| {
+                addr.checked_add(*offset as uvm)
+                    .is_some_and(|addr| vm.read_ram(addr, 1).is_ok())
+            })
+            .count();
+        let bytes = vm.read_ram(addr, readable).unwrap_or_default();
+        Ok(json!({
+            "address": format!("0x{addr:X}"),
+            "data": encode_base64(bytes),
+            "unreadableBytes": count - readable,
+        }))
+    }
+
+    fn resume(&mut self, resume: &Resume) -> io::Result<()> {
+        let Some(session) = self.session.as_mut() else {
+            return Ok(());
+        };
+        let result = match resume {
+            Resume::StepOver => session.step_over().map(|_| Stop::Done),
+            Resume::StepInto => session.step_into().map(|_| Stop::Done),
+            Resume::StepOut => session.step_out().map(|_| Stop::Done),
+            Resume::Instruction => session.resume(1),
+            Resume::Continue => loop {
+                match self
+                    .session
+                    .as_mut()
+                    .map(|session| session.resume(BATCH_LEN))
+                {
+                    Some(Ok(Stop::Done)) => {
+                        self.flush_output()?;
+                        if self.pause_requested()? {
+                            break Ok(Stop::Done);
+                        }
+                    }
+                    Some(result) => break result,
+                    None => return Ok(()),
+                }
+            },
+        };
+        self.flush_output()?;
+
+        let exit_code = self.session.as_ref().and_then(Session::exit_code);
+        match (result, exit_code) {
+            (_, Some(exit_code)) => {
+                self.event("exited", json!({ "exitCode": exit_code }))?;
+                self.event("terminated", json!({}))
+            }
+            (Ok(Stop::Done), None) => self.stopped(
+                if matches!(resume, Resume::Continue) {
+                    "pause"
+                } else {
+                    "step"
+                },
+                None,
+            ),
+            (Ok(Stop::Breakpoint(_)), None) => self.stopped("breakpoint", None),
+            (Ok(Stop::Watchpoint(_)), None) => self.stopped("data breakpoint", None),
+            (Ok(Stop::Exited(_)), None) => Ok(()),
+            (Err(err), None) => {
+                self.event(
+                    "output",
+                    json!({ "category": "stderr", "output": format!("{err}\n") }),
+                )?;
+                self.stopped("exception", Some(&err))
+            }
+        }
+    }
+
+    /// Looks for a pause request among the requests received while running
+    fn pause_requested(&mut self) -> io::Result<bool> {
+        while let Ok(request) = self.requests.try_recv() {
+            if request["command"] == "pause" {
+                self.respond(&request, Ok(json!({})))?;
+                return Ok(true);
+            }
+            self.pending.push_back(request);
+        }
+        Ok(false)
+    }
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        let Some(session) = self.session.as_mut() else {
+            return Ok(());
+        };
+        let output = |stream| String::from_utf8_lossy(&session.output(stream)).into_owned();
+        let (stdout, stderr) = (output(Stream::Stdout), output(Stream::Stderr));
+        let diagnostics = output(Stream::Diagnostics);
+        let trace = session.vm_mut().trace();
+        for (category, output) in [
+            ("stdout", stdout),
+            ("stderr", stderr),
+            ("console", diagnostics + &trace),
+        ] {
+            if !output.is_empty() {
+                self.event("output", json!({ "category": category, "output": output }))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<&str>) -> io::Result<()> {
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+                "text": text,
+            }),
+        )
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        self.seq += 1;
+        let mut response = json!({
+            "seq": self.seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = message.into(),
+        }
+        write_message(&response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.seq += 1;
+        let mut message = json!({
+            "seq": self.seq,
+            "type": "event",
+            "event": event,
+        });
+        message["body"] = body;
+        write_message(&message)
+    }
+}
+
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            len = value.trim().parse::<usize>().ok();
+        }
+    }
+    let Some(len) = len else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Missing Content-Length",
+        ));
+    };
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn write_message(message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    let mut stdout = io::stdout().lock();
+    write!(stdout, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    stdout.flush()
+}
+
+/// Parses a `0x` prefixed or decimal memory or instruction reference
+fn parse_reference(value: &Value) -> Option<uvm> {
+    let str = value.as_str()?;
+    match str.strip_prefix("0x") {
+        Some(hex) => uvm::from_str_radix(hex, 16).ok(),
+        None => str.parse().ok(),
+    }
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut str = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let [a, b, c] = [0, 1, 2].map(|idx| chunk.get(idx).copied().unwrap_or_default());
+        let n = u32::from_be_bytes([0, a, b, c]);
+        for idx in 0..=chunk.len() {
+            let sextet = (n >> (18 - 6 * idx) & 0x3F) as usize;
+            str.extend(ALPHABET.get(sextet).copied().map(char::from));
+        }
+        for _ in chunk.len()..3 {
+            str.push('=');
+        }
+    }
+    str
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        for (bytes, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\x00\xFF\xFE", "AP/+"),
+        ] {
+            assert_eq!(encode_base64(bytes), encoded);
+        }
+    }
+
+    #[test]
+    fn framing() {
+        let body = r#"{"seq":1,"command":"initialize"}"#;
+        let input = format!(
+            "Content-Length: {}\r\nContent-Type: json\r\n\r\n{body}Content-Length: 2\r\n\r\n{{}}",
+            body.len()
+        );
+        let mut reader = input.as_bytes();
+        let message = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(message, json!({ "seq": 1, "command": "initialize" }));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({})));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn bad_frames() {
+        for input in [
+            "Content-Type: json\r\n\r\n{}",
+            "Content-Length: 10\r\n\r\n{}",
+            "Content-Length: 2\r\n\r\n{]",
+        ] {
+            assert!(read_message(&mut input.as_bytes()).is_err());
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use crate::{instruction::Instruction, loader, opc, uvm, vm::VM, REG_LEN};
+use std::collections::BTreeMap;
+
+/// Upper bound on instructions decoded by a single sweep from an address
+const SWEEP_LEN: usize = 32;
+/// Length of an instruction with an immediate value
+const MAX_INSTRUCTION_LEN: usize = 2 + REG_LEN;
+
+/// Instructions decoded from RAM on demand, with the bytes they were decoded
+/// from so that code modified since can be detected
+pub struct Disassembly {
+    instructions: BTreeMap<usize, (Instruction, Vec<u8>)>,
+}
+
+impl Disassembly {
+    /// Decodes the loaded program, which ends at `end`
+    pub fn new(vm: &VM, end: usize) -> Self {
+        let instructions = vm
+            .show_program()
+            .into_iter()
+            .take_while(|(_, addr)| *addr < end)
+            .filter_map(|(instruction, addr)| {
+                let bytes = vm.read_ram(addr as uvm, instruction.len()).ok()?;
+                Some((addr, (instruction, bytes.to_vec())))
+            })
+            .collect();
+        Self { instructions }
+    }
+
+    /// Instructions and their addresses, in address order
+    pub fn lines(&self) -> impl Iterator<Item = (usize, Instruction)> + '_ {
+        self.instructions
+            .iter()
+            .map(|(addr, (instruction, _))| (*addr, *instruction))
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Index of the first instruction ending after `addr`
+    pub fn position(&self, addr: usize) -> Option<usize> {
+        self.lines()
+            .position(|(start, instruction)| addr < start + instruction.len())
+    }
+
+    /// The bytes of the instruction at `addr` changed since it was decoded
+    pub fn is_stale(&self, vm: &VM, addr: usize) -> bool {
+        self.instructions.get(&addr).is_some_and(|(_, bytes)| {
+            vm.read_ram(addr as uvm, bytes.len())
+                .map_or(true, |ram| ram != bytes)
+        })
+    }
+
+    /// Decodes instructions from `addr` onwards, unless they are already
+    /// decoded and unchanged. Instructions overlapping the new ones are dropped.
+    pub fn decode_at(&mut self, vm: &VM, addr: usize) {
+        let ram = vm.ram();
+        let mut addr = addr;
+        for _ in 0..SWEEP_LEN {
+            if addr >= ram.len()
+                || self.instructions.contains_key(&addr) && !self.is_stale(vm, addr)
+            {
+                return;
+            }
+            let Some(instruction) = loader::decode(ram, addr) else {
+                return;
+            };
+            let end = addr + instruction.len();
+            let Some(bytes) = ram.get(addr..end) else {
+                return;
+            };
+            let overlapping = self
+                .instructions
+                .range(addr.saturating_sub(MAX_INSTRUCTION_LEN)..end)
+                .filter(|(start, (other, _))| **start + other.len() > addr)
+                .map(|(start, _)| *start)
+                .collect::<Vec<_>>();
+            for start in overlapping {
+                self.instructions.remove(&start);
+            }
+            self.instructions
+                .insert(addr, (instruction, bytes.to_vec()));
+            // What follows may well be data
+            if matches!(instruction.opc, opc!(HALT) | opc!(RET) | opc!(JMP)) {
+                return;
+            }
+            addr = end;
+        }
+    }
+}
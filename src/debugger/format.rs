@@ -0,0 +1,76 @@
+use crate::uvm;
+
+/// How values are shown in the debugger panes
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Hex,
+    Unsigned,
+    Signed,
+    Ascii,
+}
+
+impl Format {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Hex => Self::Unsigned,
+            Self::Unsigned => Self::Signed,
+            Self::Signed => Self::Ascii,
+            Self::Ascii => Self::Hex,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Hex => "hex",
+            Self::Unsigned => "unsigned",
+            Self::Signed => "signed",
+            Self::Ascii => "ascii",
+        }
+    }
+
+    /// Width of the widest value of `len` bytes
+    pub fn width(self, len: usize) -> usize {
+        let shift = 64 - 8 * len as u32;
+        match self {
+            Self::Hex => 2 * len,
+            Self::Unsigned => (uvm::MAX >> shift).to_string().len(),
+            Self::Signed => (i64::MIN >> shift).to_string().len(),
+            Self::Ascii => len,
+        }
+    }
+
+    /// Formats the `len` low bytes of `value`, right aligned to `width`
+    pub fn format(self, value: uvm, len: usize) -> String {
+        let shift = 64 - 8 * len as u32;
+        let width = self.width(len);
+        match self {
+            Self::Hex => format!("{:0width$X}", value << shift >> shift),
+            Self::Unsigned => format!("{:>width$}", value << shift >> shift),
+            Self::Signed => format!("{:>width$}", (value << shift).cast_signed() >> shift),
+            Self::Ascii => value
+                .to_le_bytes()
+                .iter()
+                .take(len)
+                .map(|byte| ascii(*byte))
+                .collect(),
+        }
+    }
+}
+
+/// Printable ASCII character of a byte, `.` otherwise
+pub fn ascii(byte: u8) -> char {
+    if byte.is_ascii_graphic() || byte == b' ' {
+        char::from(byte)
+    } else {
+        '.'
+    }
+}
+
+/// Little-endian value of up to 8 bytes
+pub fn le_value(bytes: &[u8]) -> uvm {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | uvm::from(*byte))
+}
@@ -0,0 +1,610 @@
+//! GDB remote serial protocol stub, see
+//! <https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html>
+
+use super::session::{Session, Stop, WatchKind, Watchpoint};
+use crate::{
+    debug_info::DebugInfo, registers::REG_COUNT, snapshot::Snapshot, uvm, vm::Stream, REG_LEN,
+};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    rc::Rc,
+};
+
+const TARGET_XML: &str = include_str!("target.xml");
+
+/// Instructions executed between two checks for an interrupt from the client
+const BATCH_LEN: usize = 4096;
+
+trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Waits for a gdb client on a local TCP port or a unix socket path, then
+/// serves it until it detaches or kills the program
+pub fn serve(
+    program: &[u8],
+    debug_info: Option<&Rc<DebugInfo>>,
+    start: Option<Snapshot>,
+    address: &str,
+) -> io::Result<()> {
+    // The unix socket file, removed after the session so that the path can be
+    // served again
+    let (connection, socket): (Box<dyn Connection>, Option<&str>) =
+        if let Ok(port) = address.parse::<u16>() {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("Waiting for gdb on {}", listener.local_addr()?);
+            (Box::new(listener.accept()?.0), None)
+        } else {
+            #[cfg(unix)]
+            {
+                let listener = std::os::unix::net::UnixListener::bind(address)?;
+                eprintln!("Waiting for gdb on {address}");
+                (Box::new(listener.accept()?.0), Some(address))
+            }
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid port {address}"),
+            ));
+        };
+
+    let result = Stub::new(
+        Session::new(program, debug_info.cloned(), start),
+        connection,
+    )
+    .run();
+    if let Some(socket) = socket {
+        fs::remove_file(socket)?;
+    }
+    result
+}
+
+struct Stub {
+    session: Session,
+    connection: Box<dyn Connection>,
+    no_ack: bool,
+    last_packet: Vec<u8>,
+}
+
+impl Stub {
+    fn new(session: Session, connection: Box<dyn Connection>) -> Self {
+        Self {
+            session,
+            connection,
+            no_ack: false,
+            last_packet: Vec::new(),
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            match self.handle(&packet)? {
+                Some(reply) => self.send(&reply)?,
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.connection.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(b'-') => {
+                    self.connection.write_all(&self.last_packet)?;
+                    continue;
+                }
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let (Some(high), Some(low)) = (self.read_byte()?, self.read_byte()?) else {
+                return Ok(None);
+            };
+
+            let checksum = std::str::from_utf8(&[high, low])
+                .ok()
+                .and_then(|str| u8::from_str_radix(str, 16).ok());
+            let valid = checksum == Some(checksum_of(&data));
+            if !self.no_ack {
+                self.connection.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.last_packet = format!("${data}#{:02x}", checksum_of(data.as_bytes())).into_bytes();
+        self.connection.write_all(&self.last_packet)?;
+        self.connection.flush()
+    }
+
+    /// Returns the reply to `packet`, or `None` when the session is over
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let Some(command) = packet.chars().next() else {
+            return Ok(Some(String::new()));
+        };
+        let args = packet.get(1..).unwrap_or_default();
+        let reply = match command {
+            '?' => self.stop_reply(Stop::Done, true),
+            'g' => self.read_registers(),
+            'G' => self.write_registers(args),
+            'p' => self.read_register(args),
+            'P' => self.write_register(args),
+            'm' => self.read_memory(args),
+            'M' => self.write_memory(args),
+            'c' | 's' => {
+                if let Some(addr) = parse_hex(args) {
+                    if self.session.vm_mut().set_reg(0, addr).is_err() {
+                        return Ok(Some(error()));
+                    }
+                }
+                self.resume(command == 's')?
+            }
+            'Z' | 'z' => self.toggle_point(command == 'Z', args),
+            'H' | 'T' => "OK".to_string(),
+            'k' => return Ok(None),
+            'D' => {
+                self.send("OK")?;
+                return Ok(None);
+            }
+            _ => match packet {
+                "qC" => "QC1".to_string(),
+                "qAttached" => "1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                "vCont?" => "vCont;c;C;s;S".to_string(),
+                "QStartNoAckMode" => {
+                    self.no_ack = true;
+                    "OK".to_string()
+                }
+                _ if packet.starts_with("qSupported") => {
+                    "PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+;vContSupported+"
+                        .to_string()
+                }
+                _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                    read_target_xml(packet.trim_start_matches("qXfer:features:read:target.xml:"))
+                }
+                _ if packet.starts_with("vCont;") => {
+                    let action = packet.trim_start_matches("vCont;").chars().next();
+                    self.resume(matches!(action, Some('s' | 'S')))?
+                }
+                _ => String::new(),
+            },
+        };
+        Ok(Some(reply))
+    }
+
+    fn resume(&mut self, single_step: bool) -> io::Result<String> {
+        let result = if single_step {
+            self.session.resume(1)
+        } else {
+            loop {
+                match self.session.resume(BATCH_LEN) {
+                    Ok(Stop::Done) => {
+                        self.flush_output()?;
+                        if self.interrupted()? {
+                            break Ok(Stop::Done);
+                        }
+                    }
+                    result => break result,
+                }
+            }
+        };
+        self.flush_output()?;
+
+        Ok(match result {
+            Ok(stop) => self.stop_reply(stop, single_step),
+            Err(err) => {
+                self.send(&format!("O{}", encode_hex(format!("{err}\n").as_bytes())))?;
+                "S04".to_string()
+            }
+        })
+    }
+
+    fn stop_reply(&self, stop: Stop, trap: bool) -> String {
+        if let Some(exit_code) = self.session.exit_code() {
+            return format!("W{:02x}", exit_code as u8);
+        }
+        match stop {
+            Stop::Done if trap => "S05".to_string(),
+            Stop::Done => "S02".to_string(),
+            Stop::Exited(exit_code) => format!("W{:02x}", exit_code as u8),
+            Stop::Breakpoint(_) => "T05swbreak:;".to_string(),
+            Stop::Watchpoint(Watchpoint { addr, kind, .. }) => {
+                let kind = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T05{kind}:{addr:x};")
+            }
+        }
+    }
+
+    /// Forwards guest output to the client console
+    fn flush_output(&mut self) -> io::Result<()> {
+        let output = [Stream::Stdout, Stream::Stderr, Stream::Diagnostics]
+            .into_iter()
+            .flat_map(|stream| self.session.output(stream))
+            .collect::<Vec<_>>();
+        // The execution trace is not forwarded
+        self.session.vm_mut().trace();
+        if output.is_empty() {
+            return Ok(());
+        }
+        self.send(&format!("O{}", encode_hex(&output)))
+    }
+
+    /// Checks for a ^C from the client without blocking
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.connection.set_nonblocking(true)?;
+        let result = self.read_byte();
+        self.connection.set_nonblocking(false)?;
+        match result {
+            Ok(byte) => Ok(byte.is_none_or(|byte| byte == 0x03)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let mut reply = String::new();
+        for value in self.session.vm().registers() {
+            reply.push_str(&encode_hex(&value.to_le_bytes()));
+        }
+        reply
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let Some(bytes) = decode_hex(args) else {
+            return error();
+        };
+        for (idx, chunk) in (0..REG_COUNT).zip(bytes.chunks_exact(REG_LEN)) {
+            let value = uvm::from_le_bytes(chunk.try_into().unwrap_or_default());
+            if self.session.vm_mut().set_reg(idx, value).is_err() {
+                return error();
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        parse_hex(args)
+            .and_then(|idx| self.session.vm().get_reg(idx).ok())
+            .map_or_else(error, |value| encode_hex(&value.to_le_bytes()))
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let Some((idx, value)) = args.split_once('=') else {
+            return error();
+        };
+        let value = decode_hex(value)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(uvm::from_le_bytes);
+        match (parse_hex(idx), value) {
+            (Some(idx), Some(value)) if self.session.vm_mut().set_reg(idx, value).is_ok() => {
+                "OK".to_string()
+            }
+            _ => error(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_range(args) else {
+            return error();
+        };
+        self.session
+            .vm()
+            .read_ram(addr, len)
+            .map_or_else(|_| error(), encode_hex)
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return error();
+        };
+        match (parse_range(range), decode_hex(data)) {
+            (Some((addr, len)), Some(bytes))
+                if bytes.len() == len && self.session.vm_mut().write_ram(addr, &bytes).is_ok() =>
+            {
+                "OK".to_string()
+            }
+            _ => error(),
+        }
+    }
+
+    fn toggle_point(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (
+            fields.next(),
+            fields.next().and_then(parse_hex),
+            fields.next().and_then(parse_hex),
+        ) else {
+            return error();
+        };
+        let kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.session.add_breakpoint(addr);
+                } else {
+                    self.session.remove_breakpoint(addr);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let watchpoint = Watchpoint {
+            addr,
+            len: len as usize,
+            kind,
+        };
+        if insert {
+            self.session.add_watchpoint(watchpoint);
+        } else {
+            self.session.remove_watchpoint(watchpoint);
+        }
+        "OK".to_string()
+    }
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut str, byte| {
+        let _ = write!(str, "{byte:02x}");
+        str
+    })
+}
+
+fn decode_hex(str: &str) -> Option<Vec<u8>> {
+    (0..str.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(str.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(str: &str) -> Option<uvm> {
+    uvm::from_str_radix(str, 16).ok()
+}
+
+fn parse_range(str: &str) -> Option<(uvm, usize)> {
+    let (addr, len) = str.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)? as usize))
+}
+
+/// Answers `qXfer:features:read:target.xml:offset,length`
+fn read_target_xml(args: &str) -> String {
+    let Some((offset, len)) = parse_range(args) else {
+        return error();
+    };
+    let offset = (offset as usize).min(TARGET_XML.len());
+    let end = offset.saturating_add(len).min(TARGET_XML.len());
+    let chunk = TARGET_XML.get(offset..end).unwrap_or_default();
+    format!("{}{chunk}", if end == TARGET_XML.len() { 'l' } else { 'm' })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, io::Cursor};
+
+    /// Connection reading scripted client bytes and keeping what is sent
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Pipe {
+        fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Stub over a program of two NOPs, receiving `input` from the client
+    fn stub(input: &[u8]) -> (Stub, Rc<RefCell<Vec<u8>>>) {
+        let output = Rc::default();
+        let pipe = Pipe {
+            input: Cursor::new(input.to_vec()),
+            output: Rc::clone(&output),
+        };
+        let session = Session::new(&[0x80, 0, 0, 0x80, 0, 0], None, None);
+        (Stub::new(session, Box::new(pipe)), output)
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${data}#{:02x}", checksum_of(data.as_bytes()))
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(encode_hex(&[0x0A, 0xFF, 0]), "0aff00");
+        assert_eq!(decode_hex("0aFF00"), Some(vec![0x0A, 0xFF, 0]));
+        assert_eq!(decode_hex(""), Some(Vec::new()));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(parse_range("10,4"), Some((0x10, 4)));
+        assert_eq!(parse_range("10"), None);
+    }
+
+    #[test]
+    fn framing() {
+        assert_eq!(checksum_of(b"OK"), 0x9A);
+        let input = format!("+{}$g#00{}", packet("m0,2"), packet("?"));
+        let (mut stub, output) = stub(input.as_bytes());
+        assert_eq!(stub.receive().unwrap().as_deref(), Some("m0,2"));
+        // The bad checksum is refused, and the next packet received
+        assert_eq!(stub.receive().unwrap().as_deref(), Some("?"));
+        assert_eq!(stub.receive().unwrap(), None);
+        assert_eq!(output.take(), b"+-+");
+
+        stub.send("OK").unwrap();
+        assert_eq!(output.take(), b"$OK#9a");
+    }
+
+    #[test]
+    fn retransmit() {
+        let input = format!("-{}", packet("k"));
+        let (mut stub, output) = stub(input.as_bytes());
+        stub.send("S05").unwrap();
+        output.take();
+        assert_eq!(stub.receive().unwrap().as_deref(), Some("k"));
+        assert_eq!(output.take(), b"$S05#b8+");
+    }
+
+    #[test]
+    fn no_ack() {
+        let input = format!("{}{}", packet("QStartNoAckMode"), packet("?"));
+        let (mut stub, output) = stub(input.as_bytes());
+        stub.run().unwrap();
+        let expected = format!("+{}{}", packet("OK"), packet("S05"));
+        assert_eq!(String::from_utf8(output.take()).unwrap(), expected);
+    }
+
+    fn handle(stub: &mut Stub, packet: &str) -> String {
+        stub.handle(packet).unwrap().unwrap()
+    }
+
+    #[test]
+    fn registers() {
+        let (mut stub, _) = stub(b"");
+        let registers = handle(&mut stub, "g");
+        assert_eq!(registers.len(), REG_COUNT as usize * REG_LEN * 2);
+
+        let mut values = decode_hex(&registers).unwrap();
+        values.splice(REG_LEN * 7..REG_LEN * 8, 42u64.to_le_bytes());
+        assert_eq!(
+            handle(&mut stub, &format!("G{}", encode_hex(&values))),
+            "OK"
+        );
+        assert_eq!(stub.session.vm().get_reg(7), Ok(42));
+        assert_eq!(handle(&mut stub, "p7"), "2a00000000000000");
+        assert_eq!(handle(&mut stub, "P8=0100000000000000"), "OK");
+        assert_eq!(stub.session.vm().get_reg(8), Ok(1));
+
+        assert_eq!(handle(&mut stub, "Gzz"), "E01");
+        assert_eq!(handle(&mut stub, "p99"), "E01");
+        assert_eq!(handle(&mut stub, "P7=01"), "E01");
+    }
+
+    #[test]
+    fn memory() {
+        let (mut stub, _) = stub(b"");
+        assert_eq!(handle(&mut stub, "m0,3"), "800000");
+        assert_eq!(handle(&mut stub, "M100,2:2a2b"), "OK");
+        assert_eq!(handle(&mut stub, "m100,2"), "2a2b");
+
+        assert_eq!(handle(&mut stub, "M100,3:2a2b"), "E01");
+        assert_eq!(handle(&mut stub, "m100"), "E01");
+        assert_eq!(handle(&mut stub, "mffffffffffffffff,2"), "E01");
+        assert_eq!(handle(&mut stub, "Mffffffffffffffff,1:00"), "E01");
+    }
+
+    #[test]
+    fn points() {
+        let (mut stub, _) = stub(b"");
+        assert_eq!(handle(&mut stub, "Z0,3,1"), "OK");
+        assert_eq!(handle(&mut stub, "c"), "T05swbreak:;");
+        assert_eq!(stub.session.vm().pc(), 3);
+        assert_eq!(handle(&mut stub, "z0,3,1"), "OK");
+        assert_eq!(stub.session.breakpoints().next(), None);
+
+        let watchpoint = Watchpoint {
+            addr: 0x100,
+            len: 8,
+            kind: WatchKind::Read,
+        };
+        assert_eq!(handle(&mut stub, "Z3,100,8"), "OK");
+        assert!(stub.session.remove_watchpoint(watchpoint));
+        assert_eq!(handle(&mut stub, "Z3,100,8"), "OK");
+        assert_eq!(handle(&mut stub, "z3,100,8"), "OK");
+        assert!(!stub.session.remove_watchpoint(watchpoint));
+
+        assert_eq!(handle(&mut stub, "Z5,100,8"), "");
+        assert_eq!(handle(&mut stub, "Z2,100"), "E01");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_removed() {
+        let path = std::env::temp_dir().join(format!("vm-gdb-{}.sock", std::process::id()));
+        let address = path.to_str().unwrap().to_string();
+        let client = std::thread::spawn({
+            let path = path.clone();
+            move || {
+                let mut stream = loop {
+                    if let Ok(stream) = std::os::unix::net::UnixStream::connect(&path) {
+                        break stream;
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                };
+                stream.write_all(packet("k").as_bytes()).unwrap();
+                let mut ack = [0];
+                stream.read_exact(&mut ack).unwrap();
+            }
+        });
+        serve(&[], None, None, &address).unwrap();
+        client.join().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,14 @@
+mod command;
+pub mod dap;
+#[cfg(feature = "tui")]
+mod disassembly;
+#[cfg(feature = "tui")]
+mod format;
+pub mod gdb;
+pub mod script;
+mod session;
+#[cfg(feature = "tui")]
+mod tui;
+
+#[cfg(feature = "tui")]
+pub use tui::{post_mortem, run};
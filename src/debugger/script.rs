@@ -0,0 +1,122 @@
+//! Headless debugging: runs debugger commands from a file, for CI
+
+use super::{
+    command::{self, Reply},
+    session::{Session, Stop},
+};
+use crate::{debug_info::DebugInfo, limits::Limits, snapshot::Snapshot, vm::Stream};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    rc::Rc,
+};
+
+/// Instructions executed between two flushes of the program output
+const BATCH_LEN: usize = 4096;
+
+/// Runs each line of `script` as a debugger command, `#` starting a comment.
+/// Commands, their output and the program output are written to `transcript`.
+/// A command that runs into `limits` fails. Returns whether every command
+/// succeeded, including assertions.
+pub fn run(
+    program: &[u8],
+    debug_info: Option<&Rc<DebugInfo>>,
+    start: Option<Snapshot>,
+    limits: Limits,
+    script: &Path,
+    transcript: impl Write,
+) -> io::Result<bool> {
+    let script = fs::read_to_string(script)?;
+    let mut session = Session::new(program, debug_info.cloned(), start);
+    session.set_limits(limits);
+    let mut transcript = Transcript {
+        out: transcript,
+        line_start: true,
+    };
+    let mut failures = 0;
+
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        transcript.line(&format!("(vm) {line}"))?;
+
+        let result = match command::execute(&mut session, line) {
+            Ok(reply) if reply.resume => {
+                resume(&mut session, &reply, &mut transcript)?.map(|stop| Reply {
+                    output: vec![command::describe_stop(&session, stop)],
+                    ..Reply::default()
+                })
+            }
+            result => result,
+        };
+        transcript.program_output(&mut session)?;
+        match result {
+            Ok(reply) => {
+                for output in reply.output {
+                    transcript.line(&output)?;
+                }
+            }
+            Err(err) => {
+                transcript.line(&format!("Error at line {}: {err}", number + 1))?;
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        transcript.line(&format!("Script failed: {failures} command(s) failed"))?;
+    }
+    Ok(failures == 0)
+}
+
+fn resume(
+    session: &mut Session,
+    reply: &Reply,
+    transcript: &mut Transcript<impl Write>,
+) -> io::Result<Result<Stop, String>> {
+    let mut left = reply.limit.unwrap_or(usize::MAX);
+    while left > 0 {
+        let count = left.min(BATCH_LEN);
+        match session.resume_until(count, reply.until) {
+            Ok(Stop::Done) if reply.until == Some(session.vm().pc()) => break,
+            Ok(Stop::Done) => transcript.program_output(session)?,
+            result => return Ok(result),
+        }
+        left -= count;
+    }
+    Ok(Ok(Stop::Done))
+}
+
+struct Transcript<W: Write> {
+    out: W,
+    /// The program output so far ends with a new line
+    line_start: bool,
+}
+
+impl<W: Write> Transcript<W> {
+    fn line(&mut self, line: &str) -> io::Result<()> {
+        if !self.line_start {
+            writeln!(self.out)?;
+            self.line_start = true;
+        }
+        writeln!(self.out, "{line}")
+    }
+
+    /// Writes what the program printed, as is. The execution trace is
+    /// dropped.
+    fn program_output(&mut self, session: &mut Session) -> io::Result<()> {
+        session.vm_mut().trace();
+        let output = [Stream::Stdout, Stream::Stderr, Stream::Diagnostics]
+            .into_iter()
+            .flat_map(|stream| session.output(stream))
+            .collect::<Vec<_>>();
+        if !output.is_empty() {
+            self.out.write_all(&output)?;
+            self.line_start = output.ends_with(b"\n");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,436 @@
+use crate::{
+    debug_info::DebugInfo,
+    instruction::Instruction,
+    limits::Limits,
+    opc,
+    snapshot::Snapshot,
+    uvm,
+    vm::{Stream, VM},
+    REG_LEN,
+};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, VecDeque},
+    io::{self, Write},
+    rc::Rc,
+};
+
+/// Upper bound on instructions executed by a single source-level step
+const STEP_LIMIT: usize = 1_000_000;
+/// Register changes kept in the history
+const HISTORY_LEN: usize = 1024;
+/// Error of anything that would modify a read-only session
+pub const READ_ONLY: &str = "Read-only post-mortem session";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: uvm,
+    pub len: usize,
+    pub kind: WatchKind,
+}
+
+/// Register written by an instruction
+#[cfg_attr(not(feature = "tui"), allow(unused))]
+#[derive(Clone, Copy)]
+pub struct RegisterChange {
+    /// Number of instructions executed before this one
+    pub step: usize,
+    pub pc: uvm,
+    pub instruction: Instruction,
+    pub reg: uvm,
+    pub old: uvm,
+    pub new: uvm,
+}
+
+/// Output kept in memory until the front end takes it
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+    fn take(&self) -> Vec<u8> {
+        self.0.take()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Why execution stopped
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The requested number of instructions was executed
+    Done,
+    Exited(uvm),
+    Breakpoint(uvm),
+    Watchpoint(Watchpoint),
+}
+
+pub struct Session {
+    program: Vec<u8>,
+    /// State to start from instead of the freshly loaded program
+    start: Option<Snapshot>,
+    debug_info: Option<Rc<DebugInfo>>,
+    vm: VM,
+    stdout: Capture,
+    stderr: Capture,
+    diagnostics: Capture,
+    exit_code: Option<uvm>,
+    breakpoints: BTreeSet<uvm>,
+    watchpoints: Vec<Watchpoint>,
+    steps: usize,
+    history: VecDeque<RegisterChange>,
+    /// Inspecting a core dump, the machine neither runs nor changes
+    read_only: bool,
+    /// Resource limits of the machine, given again to it on reset
+    limits: Limits,
+}
+
+impl Session {
+    pub fn new(program: &[u8], debug_info: Option<Rc<DebugInfo>>, start: Option<Snapshot>) -> Self {
+        let mut session = Self {
+            program: program.to_vec(),
+            start,
+            debug_info,
+            vm: VM::new(),
+            stdout: Capture::default(),
+            stderr: Capture::default(),
+            diagnostics: Capture::default(),
+            exit_code: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            steps: 0,
+            history: VecDeque::new(),
+            read_only: false,
+            limits: Limits::default(),
+        };
+        session.reset();
+        session
+    }
+
+    /// Read-only session over the state of a faulted program
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
+    pub fn post_mortem(
+        snapshot: Snapshot,
+        program_len: usize,
+        debug_info: Option<Rc<DebugInfo>>,
+    ) -> Self {
+        let program = snapshot.ram.get(..program_len).unwrap_or_default().to_vec();
+        let mut session = Self::new(&program, debug_info, Some(snapshot));
+        session.read_only = true;
+        session
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn reset(&mut self) {
+        self.vm = VM::new();
+        self.vm.load(&self.program);
+        for stream in [Stream::Stdout, Stream::Stderr, Stream::Diagnostics] {
+            let capture = self.capture(stream).clone();
+            self.vm.set_output(stream, Box::new(capture));
+        }
+        if let Some(snapshot) = &self.start {
+            self.vm
+                .restore(snapshot)
+                .expect("Snapshots are checked when decoded");
+        }
+        if let Some(debug_info) = &self.debug_info {
+            self.vm.set_debug_info(debug_info.clone());
+        }
+        self.vm.set_limits(self.limits);
+        self.exit_code = None;
+        self.steps = 0;
+        self.history.clear();
+    }
+
+    /// Bounds the execution of the machine from now, and again after each
+    /// reset
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.vm.set_limits(limits);
+    }
+
+    /// Replaces the machine state by a snapshot
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        self.vm.restore(snapshot)?;
+        self.exit_code = None;
+        self.history.clear();
+        Ok(())
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    /// Takes what the program wrote to a stream since the last call
+    pub fn output(&self, stream: Stream) -> Vec<u8> {
+        self.capture(stream).take()
+    }
+
+    fn capture(&self, stream: Stream) -> &Capture {
+        match stream {
+            Stream::Stdout => &self.stdout,
+            Stream::Stderr => &self.stderr,
+            Stream::Diagnostics => &self.diagnostics,
+        }
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_deref()
+    }
+
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
+    pub fn program_len(&self) -> usize {
+        self.program.len()
+    }
+
+    pub fn exit_code(&self) -> Option<uvm> {
+        self.exit_code
+    }
+
+    /// Number of instructions executed since the last reset
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Recent changes of a register, oldest first. PC is not tracked.
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
+    pub fn register_history(&self, reg: uvm) -> impl Iterator<Item = &RegisterChange> {
+        self.history.iter().filter(move |change| change.reg == reg)
+    }
+
+    /// Frames as (address, BP, function entry) triples, innermost first
+    pub fn backtrace(&self) -> Vec<(uvm, uvm, uvm)> {
+        let mut frames = Vec::new();
+        let (mut addr, mut bp) = (self.vm.pc(), self.vm.bp());
+        for frame in self.vm.frames().iter().rev() {
+            frames.push((addr, bp, frame.function));
+            (addr, bp) = (frame.call_site, frame.bp);
+        }
+        frames.push((addr, bp, 0));
+        frames
+    }
+
+    /// Source file and line of the current instruction
+    pub fn line(&self) -> Option<(uvm, usize)> {
+        self.debug_info()
+            .and_then(|info| info.line_at(self.vm.pc()))
+            .map(|entry| (entry.file, entry.line))
+    }
+
+    pub fn add_breakpoint(&mut self, addr: uvm) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: uvm) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = uvm> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    /// Executes a single machine instruction, keeping track of the call depth
+    pub fn step(&mut self) -> Result<Option<uvm>, String> {
+        if self.read_only {
+            return Err(READ_ONLY.to_string());
+        }
+        if self.exit_code.is_some() {
+            return Ok(self.exit_code);
+        }
+        if let Some(kind) = self.vm.exhausted() {
+            return Err(format!(
+                "Program stopped : {kind} reached after {} instructions",
+                self.vm.cycles()
+            ));
+        }
+        let instruction = self.next_instruction()?;
+        let pc = self.vm.pc();
+        let regs = self.vm.registers();
+        self.exit_code = self.vm.execute(instruction)?;
+        for (reg, (old, new)) in regs
+            .into_iter()
+            .zip(self.vm.registers())
+            .enumerate()
+            .skip(1)
+        {
+            if old != new {
+                if self.history.len() == HISTORY_LEN {
+                    self.history.pop_front();
+                }
+                self.history.push_back(RegisterChange {
+                    step: self.steps,
+                    pc,
+                    instruction,
+                    reg: reg as uvm,
+                    old,
+                    new,
+                });
+            }
+        }
+        self.steps += 1;
+        Ok(self.exit_code)
+    }
+
+    /// Executes up to `count` instructions, stopping early on exit, breakpoints
+    /// and watchpoints. A breakpoint on the current instruction is stepped over.
+    pub fn resume(&mut self, count: usize) -> Result<Stop, String> {
+        self.resume_until(count, None)
+    }
+
+    /// Like `resume`, also stopping with `Stop::Done` when PC reaches `until`
+    pub fn resume_until(&mut self, count: usize, until: Option<uvm>) -> Result<Stop, String> {
+        for _ in 0..count {
+            let reads = self.watched_reads()?;
+            let before = self.watched_bytes();
+            if let Some(exit_code) = self.step()? {
+                return Ok(Stop::Exited(exit_code));
+            }
+            if let Some(watchpoint) = reads {
+                return Ok(Stop::Watchpoint(watchpoint));
+            }
+            if let Some(watchpoint) = self
+                .watchpoints
+                .iter()
+                .zip(before)
+                .find(|(watchpoint, bytes)| {
+                    watchpoint.kind != WatchKind::Read
+                        && self.vm.read_ram(watchpoint.addr, watchpoint.len).ok()
+                            != bytes.as_deref()
+                })
+                .map(|(watchpoint, _)| *watchpoint)
+            {
+                return Ok(Stop::Watchpoint(watchpoint));
+            }
+            if self.breakpoints.contains(&self.vm.pc()) {
+                return Ok(Stop::Breakpoint(self.vm.pc()));
+            }
+            if until == Some(self.vm.pc()) {
+                break;
+            }
+        }
+        Ok(Stop::Done)
+    }
+
+    /// Runs until the source line changes, entering calls
+    pub fn step_into(&mut self) -> Result<Option<uvm>, String> {
+        let line = self.line();
+        self.step_until(|session| session.debug_info.is_none() || session.entered_line(line))
+    }
+
+    /// Runs until the source line changes without entering calls
+    pub fn step_over(&mut self) -> Result<Option<uvm>, String> {
+        let (line, depth) = (self.line(), self.vm.frames().len());
+        self.step_until(|session| {
+            session.vm.frames().len() <= depth
+                && (session.debug_info.is_none() || session.entered_line(line))
+        })
+    }
+
+    /// Runs until the current function returns
+    pub fn step_out(&mut self) -> Result<Option<uvm>, String> {
+        let depth = self.vm.frames().len();
+        if depth == 0 {
+            return Err("Not in a function call".to_string());
+        }
+        self.step_until(|session| session.vm.frames().len() < depth)
+    }
+
+    fn next_instruction(&self) -> Result<Instruction, String> {
+        self.vm
+            .decode()
+            .ok_or_else(|| format!("No instruction at 0x{:X}", self.vm.pc()))
+    }
+
+    fn entered_line(&self, from: Option<(uvm, usize)>) -> bool {
+        let pc = self.vm.pc();
+        match self.debug_info().and_then(|info| info.line_at(pc)) {
+            Some(entry) => Some((entry.file, entry.line)) != from || entry.start == pc,
+            None => false,
+        }
+    }
+
+    fn step_until(&mut self, stop: impl Fn(&Self) -> bool) -> Result<Option<uvm>, String> {
+        for _ in 0..STEP_LIMIT {
+            if let Some(exit_code) = self.step()? {
+                return Ok(Some(exit_code));
+            }
+            if stop(self) {
+                return Ok(None);
+            }
+        }
+        Err(format!("Step interrupted after {STEP_LIMIT} instructions"))
+    }
+
+    fn watched_bytes(&self) -> Vec<Option<Vec<u8>>> {
+        self.watchpoints
+            .iter()
+            .map(|w| self.vm.read_ram(w.addr, w.len).ok().map(<[u8]>::to_vec))
+            .collect()
+    }
+
+    /// First read or access watchpoint hit by a load from the next instruction
+    fn watched_reads(&self) -> Result<Option<Watchpoint>, String> {
+        if self.watchpoints.iter().all(|w| w.kind == WatchKind::Write) {
+            return Ok(None);
+        }
+        let Instruction { rfl, opc, val, .. } = self.next_instruction()?;
+        let addr = match opc {
+            opc!(LOAD) | opc!(DUP) if rfl => self.vm.get_reg(val)?,
+            opc!(LOAD) | opc!(DUP) => val,
+            opc!(POP) | opc!(DROP) => self.vm.sp().wrapping_sub(REG_LEN as uvm),
+            opc!(IRET) => self.vm.sp().wrapping_sub(2 * REG_LEN as uvm),
+            _ => return Ok(None),
+        };
+        Ok(self
+            .watchpoints
+            .iter()
+            .find(|w| {
+                // Compares last bytes, so that ranges ending at the top of
+                // memory do not overflow
+                let last = w.addr.saturating_add((w.len as uvm).saturating_sub(1));
+                w.kind != WatchKind::Write
+                    && addr <= last
+                    && w.addr <= addr.saturating_add(REG_LEN as uvm - 1)
+            })
+            .copied())
+    }
+}
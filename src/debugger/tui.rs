@@ -0,0 +1,1669 @@
+use super::{
+    command,
+    disassembly::Disassembly,
+    format::{self, Format},
+    session::{Session, Stop, READ_ONLY},
+};
+use crate::{
+    coredump::CoreDump,
+    debug_info::DebugInfo,
+    instruction::Instruction,
+    loader,
+    registers::{Registers, REG_COUNT},
+    snapshot::Snapshot,
+    uvm,
+    vm::{Stream, RAM_LEN, VM},
+    REG_LEN,
+};
+use ratatui::{
+    crossterm::{
+        event::{
+            self, DisableMouseCapture, EnableMouseCapture, KeyCode, KeyEventKind, MouseEvent,
+            MouseEventKind,
+        },
+        execute,
+    },
+    layout::{Constraint, Direction, Layout, Position, Rect},
+    prelude::CrosstermBackend,
+    style::{Color, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
+    DefaultTerminal, Frame, Terminal,
+};
+use std::{
+    collections::HashMap,
+    fs, io, panic,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// Lines scrolled by PAGE UP and PAGE DOWN
+const PAGE_LEN: isize = 10;
+/// Lines scrolled by a mouse wheel step
+const MOUSE_SCROLL_LEN: isize = 3;
+/// Registers on a line of the registers pane
+const REGS_PER_ROW: uvm = 3;
+/// Colors of changed values, from the latest change to the oldest still shown
+const FADE: [Color; 4] = [
+    Color::Indexed(196),
+    Color::Indexed(160),
+    Color::Indexed(124),
+    Color::Indexed(88),
+];
+/// Time between two frames when nothing happens
+const FRAME_LEN: Duration = Duration::from_millis(33);
+/// Time spent running between two frames in turbo mode
+const TURBO_FRAME_LEN: Duration = Duration::from_millis(250);
+/// Instructions run between two checks of the clock in turbo mode
+const TURBO_BATCH_LEN: usize = 4096;
+/// Lines kept in the output pane
+const OUTPUT_LEN: usize = 10_000;
+/// Smallest terminal the debugger is drawn in
+const MIN_WIDTH: u16 = 60;
+const MIN_HEIGHT: u16 = 12;
+/// Narrowest terminal the stack panes are shown in
+const STACK_MIN_WIDTH: u16 = 180;
+/// Auto-run speeds, from slowest to fastest
+const SPEEDS: [Speed; 8] = [
+    Speed::Delay(Duration::from_secs(1)),
+    Speed::Delay(Duration::from_millis(300)),
+    Speed::Delay(Duration::from_millis(100)),
+    Speed::Frame(1),
+    Speed::Frame(10),
+    Speed::Frame(100),
+    Speed::Frame(1000),
+    Speed::Turbo,
+];
+/// Index of one instruction per frame in `SPEEDS`
+const DEFAULT_SPEED: usize = 3;
+
+/// How fast instructions run automatically
+#[derive(Clone, Copy)]
+enum Speed {
+    /// One instruction at a time, waiting in between
+    Delay(Duration),
+    /// Instructions per frame
+    Frame(usize),
+    /// As many instructions as possible, redrawing now and then and
+    /// dropping the stderr trace
+    Turbo,
+}
+
+impl Speed {
+    fn name(self) -> String {
+        match self {
+            Self::Delay(delay) => format!("{} ms/instr", delay.as_millis()),
+            Self::Frame(count) => format!("{count} instr/frame"),
+            Self::Turbo => "turbo".to_string(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct DisplayState {
+    pc: usize,
+    ram_offset: usize,
+    program_offset: usize,
+    /// Selected line of the program pane
+    program_cursor: usize,
+    sources: HashMap<uvm, Vec<String>>,
+    /// Program and RAM panes recenter on every step
+    follow: bool,
+    focus: Pane,
+    /// Lines scrolled up from the bottom of the output pane
+    output_scroll: usize,
+    /// Memory range found by the last search
+    found: Option<(usize, usize)>,
+    /// Selected call stack frame, innermost first
+    frame: usize,
+    reg_format: Format,
+    ram_format: Format,
+    /// Bytes per value in the RAM pane
+    ram_group: usize,
+    /// Scrollable areas of the last frame, for mouse scrolling
+    areas: Vec<(Pane, Rect)>,
+}
+
+/// Register and RAM values of the previous frame, and the step at which each
+/// of them last changed. A step is any action executing instructions.
+struct Changes {
+    regs: Vec<uvm>,
+    ram: Vec<u8>,
+    reg_steps: Vec<Option<usize>>,
+    ram_steps: Vec<Option<usize>>,
+    step: usize,
+    executed: usize,
+}
+
+impl Changes {
+    fn new(session: &Session) -> Self {
+        let vm = session.vm();
+        Self {
+            regs: vm.registers().to_vec(),
+            ram: vm.ram().to_vec(),
+            reg_steps: vec![None; REG_COUNT as usize],
+            ram_steps: vec![None; RAM_LEN],
+            step: 0,
+            executed: session.steps(),
+        }
+    }
+
+    /// Records the values that differ from the previous frame
+    fn update(&mut self, session: &Session) {
+        if session.steps() != self.executed {
+            self.executed = session.steps();
+            self.step += 1;
+        }
+        let vm = session.vm();
+        // PC changes on every step
+        for ((old, new), step) in self
+            .regs
+            .iter_mut()
+            .zip(vm.registers())
+            .zip(&mut self.reg_steps)
+            .skip(1)
+        {
+            if *old != new {
+                *old = new;
+                *step = Some(self.step);
+            }
+        }
+        for ((old, new), step) in self.ram.iter_mut().zip(vm.ram()).zip(&mut self.ram_steps) {
+            if old != new {
+                *old = *new;
+                *step = Some(self.step);
+            }
+        }
+    }
+
+    fn style(&self, step: Option<usize>) -> Style {
+        match step.and_then(|step| FADE.get(self.step - step)) {
+            Some(color) => Style::default().fg(*color).bold(),
+            None => Style::default(),
+        }
+    }
+
+    fn reg_style(&self, idx: usize) -> Style {
+        self.style(self.reg_steps.get(idx).copied().flatten())
+    }
+
+    fn ram_style(&self, addr: usize) -> Style {
+        self.style(self.ram_steps.get(addr).copied().flatten())
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum Pane {
+    #[default]
+    Program,
+    Ram,
+    CallStack,
+    Output,
+}
+
+pub fn run(
+    program: &[u8],
+    debug_info: Option<&Rc<DebugInfo>>,
+    start: Option<Snapshot>,
+) -> io::Result<()> {
+    open(App::new(Session::new(program, debug_info.cloned(), start)))
+}
+
+/// Inspects a core dump, starting with the end of the trace and the fault
+pub fn post_mortem(core: CoreDump, debug_info: Option<&Rc<DebugInfo>>) -> io::Result<()> {
+    let session = Session::post_mortem(
+        core.snapshot,
+        core.program_len as usize,
+        debug_info.cloned(),
+    );
+    let mut app = App::new(session);
+    app.history = core
+        .trace
+        .into_iter()
+        .map(|line| Line::raw(line).yellow())
+        .collect();
+    app.history.push(Line::raw(format!(
+        "Faulted at 0x{:X} : {}",
+        app.session.vm().pc(),
+        core.instruction
+    )));
+    app.fault(core.fault);
+    open(app)
+}
+
+fn open(app: App) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    set_panic_hook();
+    terminal.clear()?;
+    execute!(io::stdout(), EnableMouseCapture)?;
+    let app_result = run_app(terminal, app);
+    execute!(io::stdout(), DisableMouseCapture)?;
+    ratatui::restore();
+    app_result
+}
+
+/// Disables mouse capture before the hook installed by `ratatui::init`
+/// restores the rest of the terminal
+fn set_panic_hook() {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = execute!(io::stdout(), DisableMouseCapture);
+        hook(info);
+    }));
+}
+
+fn run_app(mut terminal: DefaultTerminal, mut app: App) -> io::Result<()> {
+    loop {
+        draw(&mut terminal, &mut app)?;
+
+        let timeout = if app.auto && matches!(app.speed(), Speed::Turbo) {
+            Duration::ZERO
+        } else {
+            FRAME_LEN
+        };
+        if event::poll(timeout)? {
+            let quit = match event::read()? {
+                event::Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    !app.handle_key(key.code)
+                }
+                event::Event::Mouse(mouse) => {
+                    app.handle_mouse(mouse);
+                    false
+                }
+                _ => false,
+            };
+            if quit {
+                return Ok(());
+            }
+        }
+
+        app.tick();
+        app.drain_output();
+        app.changes.update(&app.session);
+    }
+}
+
+struct App {
+    session: Session,
+    disassembly: Disassembly,
+    next_instruction: Option<Instruction>,
+    last_instruction: Instruction,
+    /// Memory accessed by `next_instruction`, resolved before it executes
+    next_targets: Vec<(usize, bool)>,
+    last_targets: Vec<(usize, bool)>,
+    display_state: DisplayState,
+    auto: bool,
+    done: bool,
+    history: Vec<Line<'static>>,
+    /// Command line being edited, if any
+    prompt: Option<String>,
+    commands: Vec<String>,
+    /// Position in `commands` while browsing the command history
+    command_idx: usize,
+    edit: Option<Edit>,
+    changes: Changes,
+    /// Register whose history is shown
+    popup: Option<uvm>,
+    /// VM fault shown until a key is pressed
+    fault: Option<String>,
+    /// Index in `SPEEDS`
+    speed: usize,
+    /// When instructions last ran automatically
+    last_run: Instant,
+    /// Instructions left to run automatically
+    limit: Option<usize>,
+    /// Address at which running automatically stops
+    until: Option<uvm>,
+}
+
+/// Register or memory cell being edited
+enum Edit {
+    /// Selected register, and the hex value being typed
+    Registers { idx: uvm, input: Option<String> },
+    /// Selected address, and the high nibble already typed
+    Ram { addr: usize, high: Option<u8> },
+}
+
+impl App {
+    fn new(session: Session) -> Self {
+        let disassembly = Disassembly::new(session.vm(), session.program_len());
+        let changes = Changes::new(&session);
+        let last_instruction =
+            loader::decode(session.vm().ram(), 0).expect("Invalid program start");
+        let mut app = Self {
+            display_state: DisplayState {
+                sources: load_sources(session.debug_info()),
+                follow: true,
+                ram_group: 1,
+                ..DisplayState::default()
+            },
+            session,
+            disassembly,
+            next_instruction: None,
+            last_instruction,
+            next_targets: Vec::new(),
+            last_targets: Vec::new(),
+            auto: false,
+            done: false,
+            history: Vec::new(),
+            prompt: None,
+            commands: Vec::new(),
+            command_idx: 0,
+            edit: None,
+            changes,
+            popup: None,
+            fault: None,
+            speed: DEFAULT_SPEED,
+            last_run: Instant::now(),
+            limit: None,
+            until: None,
+        };
+        app.load_next();
+        app
+    }
+
+    /// Returns `false` when the debugger should quit
+    fn handle_key(&mut self, code: KeyCode) -> bool {
+        if self.popup.take().is_some() || self.fault.take().is_some() {
+            return true;
+        }
+        if self.prompt.is_some() {
+            self.handle_prompt_key(code);
+            return true;
+        }
+        if self.edit.is_some() {
+            self.handle_edit_key(code);
+            return true;
+        }
+
+        if self.handle_navigation_key(code) {
+            return true;
+        }
+        if self.session.is_read_only()
+            && matches!(
+                code,
+                KeyCode::Enter | KeyCode::Char(' ' | 's' | 'n' | 'o' | 'c' | 'e' | 'm')
+            )
+        {
+            self.history.push(Line::raw(READ_ONLY).red());
+            return true;
+        }
+
+        match code {
+            KeyCode::Char('q') => return false,
+            KeyCode::Char('r') => {
+                self.done = false;
+                self.session.reset();
+                self.disassembly = Disassembly::new(self.session.vm(), self.session.program_len());
+                self.changes = Changes::new(&self.session);
+                self.history = Vec::new();
+                self.load_next();
+            }
+            KeyCode::Enter => {
+                self.auto = !self.auto;
+                self.limit = None;
+                self.until = None;
+            }
+            KeyCode::Char(' ') => {
+                if self.done {
+                    // Nothing left to execute
+                } else if self.next_instruction.is_some() {
+                    self.execute(Session::step);
+                    self.next_instruction = None;
+                } else {
+                    self.load_next();
+                }
+            }
+            KeyCode::Char(key @ ('s' | 'n' | 'o')) => {
+                if !self.done {
+                    self.execute(match key {
+                        's' => Session::step_into,
+                        'n' => Session::step_over,
+                        _ => Session::step_out,
+                    });
+                    self.load_next();
+                }
+            }
+            KeyCode::Char('e') => {
+                self.edit = Some(Edit::Registers {
+                    idx: 0,
+                    input: None,
+                });
+            }
+            KeyCode::Char('m') => {
+                self.edit = Some(Edit::Ram {
+                    addr: self.display_state.ram_offset,
+                    high: None,
+                });
+            }
+            KeyCode::Char('c') => {
+                let cursor = self.display_state.program_cursor;
+                let addr = self.disassembly.lines().nth(cursor).map(|(addr, _)| addr);
+                if let Some(addr) = addr {
+                    self.run_command(&format!("until *0x{addr:X}"));
+                }
+            }
+            KeyCode::Char(':') => self.open_prompt(""),
+            _ => return true,
+        }
+        if !matches!(code, KeyCode::Enter | KeyCode::Char('c')) {
+            self.auto = false;
+        }
+        true
+    }
+
+    /// Scrolls and moves around panes, without affecting execution
+    fn handle_navigation_key(&mut self, code: KeyCode) -> bool {
+        let focus = self.display_state.focus;
+        match code {
+            KeyCode::Tab => {
+                self.display_state.focus = match focus {
+                    Pane::Program => Pane::Ram,
+                    Pane::Ram => Pane::CallStack,
+                    Pane::CallStack => Pane::Output,
+                    Pane::Output => Pane::Program,
+                };
+            }
+            KeyCode::Up => self.scroll(focus, -1),
+            KeyCode::Down => self.scroll(focus, 1),
+            KeyCode::PageUp => self.scroll(focus, -PAGE_LEN),
+            KeyCode::PageDown => self.scroll(focus, PAGE_LEN),
+            KeyCode::Char('f') => {
+                self.display_state.follow = !self.display_state.follow;
+                self.display_state.output_scroll = 0;
+                self.display_state.found = None;
+            }
+            KeyCode::Char('v') => {
+                self.display_state.reg_format = self.display_state.reg_format.next();
+            }
+            KeyCode::Char('x') => {
+                self.display_state.ram_format = self.display_state.ram_format.next();
+            }
+            KeyCode::Char('w') => {
+                self.display_state.ram_group = match self.display_state.ram_group {
+                    1 => 2,
+                    2 => 4,
+                    4 => 8,
+                    _ => 1,
+                };
+            }
+            KeyCode::Char('+' | '=') => self.speed = (self.speed + 1).min(SPEEDS.len() - 1),
+            KeyCode::Char('-') => self.speed = self.speed.saturating_sub(1),
+            KeyCode::Char('g') => self.open_prompt("view "),
+            KeyCode::Char('/') => self.open_prompt("find "),
+            KeyCode::Char('S') => self.open_prompt("save "),
+            KeyCode::Char('L') => self.open_prompt("load "),
+            _ => return false,
+        }
+        true
+    }
+
+    fn handle_mouse(&mut self, mouse: MouseEvent) {
+        let lines = match mouse.kind {
+            MouseEventKind::ScrollUp => -MOUSE_SCROLL_LEN,
+            MouseEventKind::ScrollDown => MOUSE_SCROLL_LEN,
+            _ => return,
+        };
+        let position = Position::new(mouse.column, mouse.row);
+        if let Some((pane, _)) = self
+            .display_state
+            .areas
+            .iter()
+            .find(|(_, area)| area.contains(position))
+        {
+            self.scroll(*pane, lines);
+        }
+    }
+
+    fn scroll(&mut self, pane: Pane, lines: isize) {
+        let display_state = &mut self.display_state;
+        match pane {
+            Pane::Program => {
+                display_state.follow = false;
+                display_state.program_cursor = display_state
+                    .program_cursor
+                    .saturating_add_signed(lines)
+                    .min(self.disassembly.len().saturating_sub(1));
+            }
+            Pane::Ram => {
+                display_state.follow = false;
+                display_state.ram_offset = display_state
+                    .ram_offset
+                    .saturating_add_signed(lines * 16)
+                    .min((RAM_LEN - 1) / 16 * 16);
+            }
+            Pane::CallStack => {
+                display_state.frame = display_state
+                    .frame
+                    .saturating_add_signed(lines)
+                    .min(self.session.backtrace().len() - 1);
+            }
+            Pane::Output => {
+                display_state.output_scroll = display_state
+                    .output_scroll
+                    .saturating_add_signed(-lines)
+                    .min(self.history.len());
+            }
+        }
+    }
+
+    /// Moves the Program and RAM panes to a memory range
+    fn view(&mut self, addr: usize, len: usize) {
+        let display_state = &mut self.display_state;
+        display_state.follow = false;
+        display_state.ram_offset = (addr - addr % 16).saturating_sub(4 * 16);
+        if let Some(idx) = self.disassembly.position(addr) {
+            display_state.program_offset = idx;
+            display_state.program_cursor = idx;
+        }
+        display_state.found = Some((addr, len));
+    }
+
+    fn open_prompt(&mut self, input: &str) {
+        self.prompt = Some(input.to_string());
+        self.command_idx = self.commands.len();
+    }
+
+    fn handle_prompt_key(&mut self, code: KeyCode) {
+        let Some(prompt) = self.prompt.as_mut() else {
+            return;
+        };
+        match code {
+            KeyCode::Esc => self.prompt = None,
+            KeyCode::Backspace if prompt.is_empty() => self.prompt = None,
+            KeyCode::Backspace => {
+                prompt.pop();
+            }
+            KeyCode::Char(c) => prompt.push(c),
+            KeyCode::Tab => {
+                let candidates = command::complete(prompt);
+                if let Some(first) = candidates.first() {
+                    let common = candidates.iter().fold(first.as_str(), |common, candidate| {
+                        let len = common
+                            .chars()
+                            .zip(candidate.chars())
+                            .take_while(|(a, b)| a == b)
+                            .count();
+                        common.get(..len).unwrap_or_default()
+                    });
+                    *prompt = common.to_string();
+                }
+                if candidates.len() > 1 {
+                    let candidates = candidates
+                        .iter()
+                        .map(|candidate| candidate.split(' ').nth_back(1).unwrap_or_default())
+                        .collect::<Vec<_>>()
+                        .join("  ");
+                    self.history.push(Line::raw(candidates).cyan());
+                }
+            }
+            KeyCode::Up | KeyCode::Down => {
+                self.command_idx = if code == KeyCode::Up {
+                    self.command_idx.saturating_sub(1)
+                } else {
+                    (self.command_idx + 1).min(self.commands.len())
+                };
+                *prompt = self
+                    .commands
+                    .get(self.command_idx)
+                    .cloned()
+                    .unwrap_or_default();
+            }
+            KeyCode::Enter => {
+                let line = prompt.clone();
+                self.prompt = None;
+                self.run_command(&line);
+            }
+            _ => (),
+        }
+    }
+
+    fn handle_edit_key(&mut self, code: KeyCode) {
+        let Some(edit) = self.edit.as_mut() else {
+            return;
+        };
+        let digit = match code {
+            KeyCode::Char(c) => c.to_digit(16),
+            _ => None,
+        };
+        match edit {
+            Edit::Registers {
+                idx,
+                input: Some(input),
+            } => match code {
+                KeyCode::Char(c) if digit.is_some() && input.len() < REG_LEN * 2 => input.push(c),
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Esc => {
+                    *edit = Edit::Registers {
+                        idx: *idx,
+                        input: None,
+                    }
+                }
+                KeyCode::Enter => {
+                    let (idx, value) = (*idx, uvm::from_str_radix(input, 16).unwrap_or_default());
+                    *edit = Edit::Registers { idx, input: None };
+                    match self.session.vm_mut().set_reg(idx, value) {
+                        Ok(()) => self.history.push(
+                            Line::raw(format!("{} = 0x{value:X}", Registers::register_name(idx)))
+                                .cyan(),
+                        ),
+                        Err(err) => self.history.push(Line::raw(err).red()),
+                    }
+                    self.load_next();
+                }
+                _ => (),
+            },
+            Edit::Registers { idx, input: None } => match code {
+                KeyCode::Char('h') => self.popup = Some(*idx),
+                KeyCode::Left => *idx = idx.saturating_sub(1),
+                KeyCode::Right => *idx = (*idx + 1).min(REG_COUNT - 1),
+                KeyCode::Up => *idx = idx.saturating_sub(REGS_PER_ROW),
+                KeyCode::Down => *idx = (*idx + REGS_PER_ROW).min(REG_COUNT - 1),
+                KeyCode::Char(c) if digit.is_some() => {
+                    *edit = Edit::Registers {
+                        idx: *idx,
+                        input: Some(c.to_string()),
+                    };
+                }
+                KeyCode::Enter => {
+                    *edit = Edit::Registers {
+                        idx: *idx,
+                        input: Some(String::new()),
+                    };
+                }
+                KeyCode::Tab => {
+                    *edit = Edit::Ram {
+                        addr: self.display_state.ram_offset,
+                        high: None,
+                    };
+                }
+                KeyCode::Esc => self.edit = None,
+                _ => (),
+            },
+            Edit::Ram { addr, high } => match code {
+                KeyCode::Left => *addr = addr.saturating_sub(1),
+                KeyCode::Right => *addr = (*addr + 1).min(RAM_LEN - 1),
+                KeyCode::Up => *addr = addr.saturating_sub(16),
+                KeyCode::Down => *addr = (*addr + 16).min(RAM_LEN - 1),
+                KeyCode::PageUp => *addr = addr.saturating_sub(256),
+                KeyCode::PageDown => *addr = (*addr + 256).min(RAM_LEN - 1),
+                KeyCode::Char(_) => {
+                    let Some(digit) = digit else {
+                        return;
+                    };
+                    if let Some(high) = high.take() {
+                        let byte = high << 4 | digit as u8;
+                        if let Err(err) = self.session.vm_mut().write_ram(*addr as uvm, &[byte]) {
+                            self.history.push(Line::raw(err).red());
+                        }
+                        *addr = (*addr + 1).min(RAM_LEN - 1);
+                        self.load_next();
+                    } else {
+                        *high = Some(digit as u8);
+                    }
+                }
+                KeyCode::Tab => {
+                    *edit = Edit::Registers {
+                        idx: 0,
+                        input: None,
+                    };
+                }
+                KeyCode::Esc if high.is_some() => *high = None,
+                KeyCode::Esc => self.edit = None,
+                _ => (),
+            },
+        }
+    }
+
+    fn run_command(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        if self.commands.last().is_none_or(|last| last != line) {
+            self.commands.push(line.to_string());
+        }
+        self.history.push(Line::raw(format!(":{line}")).cyan());
+
+        let result = command::execute(&mut self.session, line);
+        self.drain_output();
+        match result {
+            Ok(reply) => {
+                self.history
+                    .extend(reply.output.into_iter().map(|line| Line::raw(line).cyan()));
+                self.auto = reply.resume;
+                self.limit = reply.limit;
+                self.until = reply.until;
+                if let Some((addr, len)) = reply.view {
+                    self.view(addr as usize, len);
+                }
+            }
+            Err(err) => self.history.push(Line::raw(err).red()),
+        }
+        self.done = self.session.exit_code().is_some();
+        self.load_next();
+    }
+
+    fn speed(&self) -> Speed {
+        SPEEDS.get(self.speed).copied().unwrap_or(Speed::Turbo)
+    }
+
+    /// Runs instructions at the selected speed when running automatically
+    fn tick(&mut self) {
+        if !self.auto || self.done {
+            return;
+        }
+        let (count, deadline) = match self.speed() {
+            Speed::Delay(delay) if self.last_run.elapsed() < delay => return,
+            Speed::Delay(_) => (1, None),
+            Speed::Frame(count) => (count, None),
+            Speed::Turbo => (usize::MAX, Some(Instant::now() + TURBO_FRAME_LEN)),
+        };
+        self.last_run = Instant::now();
+        let mut left = count
+            .min(self.limit.unwrap_or(usize::MAX))
+            .saturating_sub(1);
+        while left > 0 && deadline.is_none_or(|deadline| Instant::now() < deadline) {
+            let batch = left.min(TURBO_BATCH_LEN);
+            if !self.run(batch) {
+                return;
+            }
+            left -= batch;
+            if deadline.is_some() {
+                self.session.vm_mut().trace();
+            }
+        }
+        // The last instruction runs on its own so that what it accessed is shown
+        self.load_next();
+        if self.next_instruction.is_some() {
+            self.run(1);
+        }
+    }
+
+    /// Runs up to `count` instructions, returns `false` once stopped
+    fn run(&mut self, count: usize) -> bool {
+        let steps = self.session.steps();
+        let result = self.session.resume_until(count, self.until);
+        if let Some(limit) = &mut self.limit {
+            *limit = limit.saturating_sub(self.session.steps() - steps);
+        }
+        let reached = self.until == Some(self.session.vm().pc()) || self.limit == Some(0);
+        match result {
+            Ok(Stop::Done) if !reached => return true,
+            Ok(Stop::Exited(exit_code)) => {
+                self.history
+                    .push(Line::raw(format!("Program exited with code : {exit_code}")));
+                self.done = true;
+            }
+            Ok(stop) => {
+                self.drain_output();
+                self.history
+                    .push(Line::raw(command::describe_stop(&self.session, stop)).cyan());
+                self.auto = false;
+                self.load_next();
+            }
+            Err(err) => self.fault(err),
+        }
+        false
+    }
+
+    fn drain_output(&mut self) {
+        for (stream, color) in [
+            (Stream::Stdout, Color::Reset),
+            (Stream::Stderr, Color::Red),
+            (Stream::Diagnostics, Color::Magenta),
+        ] {
+            String::from_utf8_lossy(&self.session.output(stream))
+                .lines()
+                .for_each(|l| self.history.push(Line::raw(l.to_owned()).fg(color)));
+        }
+
+        self.session
+            .vm_mut()
+            .trace()
+            .lines()
+            .for_each(|l| self.history.push(Line::raw(l.to_owned()).yellow()));
+
+        let excess = self.history.len().saturating_sub(OUTPUT_LEN);
+        self.history.drain(..excess);
+
+        if let Some(instruction) = self.next_instruction {
+            self.last_instruction = instruction;
+            self.last_targets.clone_from(&self.next_targets);
+        }
+    }
+
+    fn execute(&mut self, step: fn(&mut Session) -> Result<Option<uvm>, String>) {
+        match step(&mut self.session) {
+            Ok(Some(exit_code)) => {
+                self.history
+                    .push(Line::raw(format!("Program exited with code : {exit_code}")));
+                self.done = true;
+            }
+            Ok(None) => (),
+            Err(err) => self.fault(err),
+        }
+    }
+
+    fn fault(&mut self, err: String) {
+        self.history.push(Line::raw(err.clone()).red());
+        self.fault = Some(err);
+        self.auto = false;
+    }
+
+    fn load_next(&mut self) {
+        let vm = self.session.vm();
+        if let Some(instruction) = vm.decode() {
+            self.disassembly.decode_at(vm, vm.pc() as usize);
+            if let Some((rfl, val)) = instruction.target_addr() {
+                if let Ok(target) = if rfl { vm.get_reg(val) } else { Ok(val) } {
+                    self.disassembly.decode_at(vm, target as usize);
+                }
+            }
+            self.next_instruction = Some(instruction);
+            self.next_targets = resolve_targets(vm, instruction);
+            self.display_state.pc = vm.pc() as usize;
+            self.display_state.frame = 0;
+        }
+    }
+}
+
+/// Addresses accessed by an instruction that has yet to execute
+fn resolve_targets(vm: &VM, instruction: Instruction) -> Vec<(usize, bool)> {
+    instruction
+        .target_ram()
+        .into_iter()
+        .filter_map(|(rfl, val, offset, write)| {
+            let addr = if rfl { vm.get_reg(val).ok()? } else { val };
+            Some((addr.wrapping_add_signed(offset) as usize, write))
+        })
+        .collect()
+}
+
+fn load_sources(debug_info: Option<&DebugInfo>) -> HashMap<uvm, Vec<String>> {
+    let Some(debug_info) = debug_info else {
+        return HashMap::new();
+    };
+    debug_info
+        .files()
+        .map(|(file, name)| {
+            let lines = debug_info
+                .source_path(file)
+                .and_then(|path| fs::read_to_string(path).ok())
+                .map_or_else(
+                    || vec![format!("Source not found : {name}")],
+                    |source| source.lines().map(str::to_string).collect(),
+                );
+            (file, lines)
+        })
+        .collect()
+}
+
+#[allow(clippy::indexing_slicing)]
+fn draw(
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    app: &mut App,
+) -> Result<(), io::Error> {
+    let mode = app.next_instruction.is_some();
+    let speed = app.speed();
+    let session = &app.session;
+    let vm = session.vm();
+    let disassembly = &app.disassembly;
+    let instruction = app.last_instruction;
+    let display_state = &mut app.display_state;
+    let history = &app.history;
+    terminal.draw(|frame| {
+        if draw_too_small(frame) {
+            display_state.areas = Vec::new();
+            return;
+        }
+        let area = frame.area();
+        let layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![Constraint::Fill(1), Constraint::Length(1)])
+            .split(area);
+
+        frame.render_widget(
+            controls(
+                app.prompt.as_deref(),
+                app.edit.as_ref(),
+                app.session.is_read_only(),
+            ),
+            layout[1],
+        );
+
+        let hlayout = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints(vec![
+                Constraint::Percentage(0),
+                Constraint::Length(32),
+                if display_state.sources.is_empty() {
+                    Constraint::Length(0)
+                } else {
+                    Constraint::Fill(1)
+                },
+                // Leaves room for the program pane
+                Constraint::Length(ram_width(display_state).max(78).min(area.width - 32)),
+                Constraint::Length(if area.width < STACK_MIN_WIDTH { 0 } else { 44 }),
+                Constraint::Fill(1),
+                Constraint::Fill(0),
+            ])
+            .split(layout[0]);
+
+        draw_program(
+            frame,
+            vm,
+            disassembly,
+            mode,
+            instruction,
+            display_state,
+            hlayout[1],
+        );
+
+        draw_source(frame, session, display_state, hlayout[2]);
+
+        let mem_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![Constraint::Length(7), Constraint::Fill(1)])
+            .split(hlayout[3]);
+
+        let registers_display = Paragraph::new(format_regs(
+            &vm.registers(),
+            instruction.target_regs(),
+            app.edit.as_ref(),
+            &app.changes,
+            display_state.reg_format,
+        ))
+        .block(
+            Block::new()
+                .title(format!("Registers ({})", display_state.reg_format.name()))
+                .borders(Borders::ALL),
+        );
+        frame.render_widget(registers_display, mem_layout[0]);
+
+        let cursor = match &app.edit {
+            Some(Edit::Ram { addr, high }) => Some((*addr, *high)),
+            _ => None,
+        };
+        let ram_title = ram_title(display_state, cursor.is_some());
+        let ram_display = Paragraph::new(format_ram(
+            vm.ram(),
+            &app.last_targets,
+            mem_layout[1].height,
+            display_state,
+            cursor,
+            &app.changes,
+        ))
+        .block(pane_block(ram_title, display_state.focus == Pane::Ram));
+        frame.render_widget(ram_display, mem_layout[1]);
+
+        let call_stack_area = draw_stacks(frame, session, display_state, hlayout[4]);
+
+        draw_output(frame, history, display_state, speed, hlayout[5]);
+
+        if let Some(reg) = app.popup {
+            draw_history(frame, session, reg);
+        }
+        if let Some(fault) = &app.fault {
+            draw_fault(frame, session, fault);
+        }
+
+        display_state.areas = vec![
+            (Pane::Program, hlayout[1]),
+            (Pane::Ram, mem_layout[1]),
+            (Pane::CallStack, call_stack_area),
+            (Pane::Output, hlayout[5]),
+        ];
+    })?;
+
+    Ok(())
+}
+
+fn draw_program(
+    frame: &mut Frame,
+    vm: &VM,
+    disassembly: &Disassembly,
+    mode: bool,
+    instruction: Instruction,
+    display_state: &mut DisplayState,
+    area: Rect,
+) {
+    let program_str = disassembly
+        .lines()
+        .map(|(addr, instr)| (format!("{instr:?}"), addr, disassembly.is_stale(vm, addr)))
+        .collect::<Vec<_>>();
+    let program_jmp = instruction.target_addr();
+    let program_display = Paragraph::new(format_program(
+        vm,
+        &program_str,
+        mode,
+        area.height,
+        display_state,
+        program_jmp,
+    ))
+    .block(pane_block(
+        program_title(vm, display_state),
+        display_state.focus == Pane::Program,
+    ));
+    frame.render_widget(program_display, area);
+}
+
+/// Replaces the panes by a message in terminals too small for them
+fn draw_too_small(frame: &mut Frame) -> bool {
+    let area = frame.area();
+    if area.width >= MIN_WIDTH && area.height >= MIN_HEIGHT {
+        return false;
+    }
+    let message = format!(
+        "Terminal too small ({}x{}), needs {MIN_WIDTH}x{MIN_HEIGHT}",
+        area.width, area.height
+    );
+    frame.render_widget(Paragraph::new(message).wrap(Wrap { trim: false }), area);
+    true
+}
+
+/// Popup listing the recent changes of a register
+fn draw_history(frame: &mut Frame, session: &Session, reg: uvm) {
+    let area = frame.area();
+    let mut lines = session
+        .register_history(reg)
+        .map(|change| {
+            Line::raw(format!(
+                " {:>8}  {:08X}  {:<20}  {:X} -> {:X}",
+                change.step,
+                change.pc,
+                format!("{:?}", change.instruction),
+                change.old,
+                change.new
+            ))
+        })
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        lines.push(Line::raw(" No recorded change").dark_gray());
+    }
+    let height = (lines.len() as u16 + 2).min(area.height);
+    let popup = centered(area, 72, height);
+    // Most recent changes last, keeping them in view
+    let skip = lines
+        .len()
+        .saturating_sub(usize::from(height.saturating_sub(2)));
+    frame.render_widget(Clear, popup);
+    frame.render_widget(
+        Paragraph::new(Text::from(lines.split_off(skip))).block(
+            Block::new()
+                .title(format!(
+                    "{} history (step, PC, instruction)",
+                    Registers::register_name(reg)
+                ))
+                .borders(Borders::ALL)
+                .border_style(Style::default().cyan()),
+        ),
+        popup,
+    );
+}
+
+/// Modal pane describing a VM fault
+fn draw_fault(frame: &mut Frame, session: &Session, fault: &str) {
+    let vm = session.vm();
+    let instruction = vm
+        .decode()
+        .map_or_else(|| "?".to_string(), |instruction| format!("{instruction:?}"));
+    let text = Text::from(vec![
+        Line::raw(fault.to_string()),
+        Line::raw(""),
+        Line::raw(format!("PC {:08X}  {instruction}", vm.pc())),
+        Line::raw(""),
+        Line::raw("Press any key to inspect the machine").dark_gray(),
+    ]);
+    let popup = centered(frame.area(), 60, 9);
+    frame.render_widget(Clear, popup);
+    frame.render_widget(
+        Paragraph::new(text).wrap(Wrap { trim: false }).block(
+            Block::new()
+                .title("Fault")
+                .borders(Borders::ALL)
+                .border_style(Style::default().red()),
+        ),
+        popup,
+    );
+}
+
+/// Area of at most `width` by `height` in the middle of `area`
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    )
+}
+
+fn controls<'a>(prompt: Option<&'a str>, edit: Option<&Edit>, read_only: bool) -> Paragraph<'a> {
+    match (prompt, edit) {
+        (Some(prompt), _) => Paragraph::new(Line::from(vec![
+            Span::raw(format!(":{prompt}")),
+            Span::styled(" ", Style::default().on_white()),
+        ])),
+        (None, Some(Edit::Registers { .. })) => Paragraph::new(
+            " Edit registers   Select [ARROWS]   Type hex value   Confirm [ENTER]   History [h]   RAM [TAB]   Back [ESC]",
+        ),
+        (None, Some(Edit::Ram { .. })) => Paragraph::new(
+            " Edit RAM   Move [ARROWS/PAGE UP/PAGE DOWN]   Type hex bytes   Registers [TAB]   Back [ESC]",
+        ),
+        (None, None) if read_only => Paragraph::new(Line::from(vec![
+            Span::raw(" Post-mortem ").black().on_red(),
+            Span::raw("   Quit [q]   Command [:]   Focus [TAB]   Scroll [ARROWS]   Follow PC [f]   Goto [g]   Search [/]   Formats [v/x/w]   Save snapshot [S]"),
+        ])),
+        (None, None) => Paragraph::new(
+            " Quit [q]   Reset [r]   Step [SPACE]   Run/Stop [ENTER]   Step into [s]   Step over [n]   Step out [o]   Edit registers [e]   Edit RAM [m]   Command [:]   Focus [TAB]   Scroll [ARROWS]   Follow PC [f]   Goto [g]   Search [/]   Formats [v/x/w]   Speed [+/-]   Run to cursor [c]   Save/Load snapshot [S/L]",
+        ),
+    }
+}
+
+fn draw_source(frame: &mut Frame, session: &Session, display_state: &DisplayState, area: Rect) {
+    if let Some((file, line)) = session.line() {
+        let source = display_state
+            .sources
+            .get(&file)
+            .map_or(&[][..], Vec::as_slice);
+        let title = session
+            .debug_info()
+            .and_then(|info| info.file_name(file))
+            .map_or_else(|| "Source".to_string(), |name| format!("Source ({name})"));
+        let source_display = Paragraph::new(format_source(source, line, area.height))
+            .block(Block::new().title(title).borders(Borders::ALL));
+        frame.render_widget(source_display, area);
+    } else if !display_state.sources.is_empty() {
+        frame.render_widget(
+            Paragraph::new(" No line information".dark_gray())
+                .block(Block::new().title("Source").borders(Borders::ALL)),
+            area,
+        );
+    }
+}
+
+/// Stack and call stack panes, returns the area of the latter
+#[allow(clippy::indexing_slicing)]
+fn draw_stacks(
+    frame: &mut Frame,
+    session: &Session,
+    display_state: &mut DisplayState,
+    area: Rect,
+) -> Rect {
+    let layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(vec![Constraint::Fill(1), Constraint::Length(8)])
+        .split(area);
+
+    let backtrace = session.backtrace();
+    display_state.frame = display_state.frame.min(backtrace.len() - 1);
+    draw_stack(frame, session, &backtrace, display_state.frame, layout[0]);
+    let call_stack_display =
+        Paragraph::new(format_call_stack(session, &backtrace, display_state.frame)).block(
+            pane_block("Call Stack", display_state.focus == Pane::CallStack),
+        );
+    frame.render_widget(call_stack_display, layout[1]);
+    layout[1]
+}
+
+/// Words from just above SP down to the bottom of the stack, with frame
+/// boundaries and the locals of the selected frame
+fn draw_stack(
+    frame: &mut Frame,
+    session: &Session,
+    backtrace: &[(uvm, uvm, uvm)],
+    selected: usize,
+    area: Rect,
+) {
+    let vm = session.vm();
+    let info = session.debug_info();
+    let (sp, bp) = (vm.sp(), vm.bp());
+    let locals = backtrace
+        .get(selected)
+        .and_then(|(addr, bp, _)| {
+            let function = info?.function_at(*addr)?;
+            Some(
+                function
+                    .locals
+                    .iter()
+                    .map(|local| (bp.wrapping_add_signed(local.bp_offset), local.name.as_str()))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .unwrap_or_default();
+
+    let word = REG_LEN as uvm;
+    let base = session.program_len() as uvm;
+    let top = sp.saturating_add(word).min((RAM_LEN - REG_LEN) as uvm);
+    let local_style = Style::default().black().on_magenta();
+    let mut lines = Vec::new();
+    let mut addr = top;
+    while addr >= base && lines.len() < usize::from(area.height) {
+        let value = vm
+            .read_ram(addr, REG_LEN)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map_or_else(
+                || "?".repeat(16),
+                |bytes| format!("{:016X}", uvm::from_le_bytes(bytes)),
+            );
+        let mut spans = vec![Span::raw(format!(" {addr:08X}  "))];
+        match locals.iter().find(|(local, _)| *local == addr) {
+            Some((_, name)) => {
+                spans.push(Span::styled(value, local_style));
+                spans.push(Span::raw(format!(" {name}")).magenta());
+            }
+            None if addr >= sp => spans.push(Span::raw(value).dark_gray()),
+            None => spans.push(Span::raw(value)),
+        }
+        if addr == sp {
+            spans.push(Span::raw(" <SP").cyan());
+        }
+        if addr == bp {
+            spans.push(Span::raw(" <BP").cyan());
+        }
+        lines.push(Line::from(spans));
+
+        for (depth, (frame_addr, _, entry)) in backtrace
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, frame_bp, _))| *frame_bp == addr)
+        {
+            lines.push(
+                Line::raw(format!(
+                    " ──── #{depth} {}",
+                    frame_name(info, *frame_addr, *entry)
+                ))
+                .dark_gray(),
+            );
+        }
+
+        let Some(next) = addr.checked_sub(word) else {
+            break;
+        };
+        addr = next;
+    }
+
+    frame.render_widget(
+        Paragraph::new(Text::from(lines)).block(Block::new().title("Stack").borders(Borders::ALL)),
+        area,
+    );
+}
+
+fn format_call_stack<'a>(
+    session: &Session,
+    backtrace: &[(uvm, uvm, uvm)],
+    selected: usize,
+) -> Text<'a> {
+    let info = session.debug_info();
+    let lines = backtrace
+        .iter()
+        .enumerate()
+        .map(|(depth, (addr, _, entry))| {
+            let location = info
+                .and_then(|info| info.line_at(*addr))
+                .and_then(|entry| Some((info?.file_name(entry.file)?, entry.line)))
+                .map(|(file, line)| format!(" {file}:{line}"))
+                .unwrap_or_default();
+            let line = format!(
+                " #{depth} {addr:08X} {}{location}",
+                frame_name(info, *addr, *entry)
+            );
+            if depth == selected {
+                Line::styled(line, Style::default().black().on_white())
+            } else {
+                Line::raw(line)
+            }
+        })
+        .collect::<Vec<_>>();
+    Text::from(lines)
+}
+
+/// Name of the function a frame is executing, or its entry point
+fn frame_name(info: Option<&DebugInfo>, addr: uvm, entry: uvm) -> String {
+    info.and_then(|info| info.function_at(addr))
+        .map_or_else(|| format!("0x{entry:X}"), |function| function.name.clone())
+}
+
+fn draw_output(
+    frame: &mut Frame,
+    history: &[Line],
+    display_state: &DisplayState,
+    speed: Speed,
+    area: Rect,
+) {
+    let height = usize::from(area.height.saturating_sub(2));
+    let skip = history
+        .len()
+        .saturating_sub(height + display_state.output_scroll);
+    let history_display = Paragraph::new(Text::from(
+        history
+            .iter()
+            .skip(skip)
+            .take(height)
+            .map(Clone::clone)
+            .collect::<Vec<_>>(),
+    ))
+    .block(
+        pane_block("stdout", display_state.focus == Pane::Output)
+            .title("stderr".yellow())
+            .title(Line::raw(format!("Speed: {}", speed.name())).right_aligned()),
+    );
+    frame.render_widget(history_display, area);
+}
+
+fn pane_block<'a>(title: impl Into<Line<'a>>, focused: bool) -> Block<'a> {
+    let block = Block::new().title(title).borders(Borders::ALL);
+    if focused {
+        block.border_style(Style::default().cyan())
+    } else {
+        block
+    }
+}
+
+fn program_title(vm: &VM, display_state: &DisplayState) -> String {
+    let mut title = match vm
+        .debug_info()
+        .and_then(|info| info.function_at(display_state.pc as uvm))
+    {
+        Some(function) => format!("Program ({})", function.name),
+        None => "Program".to_string(),
+    };
+    if !display_state.follow {
+        title.push_str(" [manual]");
+    }
+    title
+}
+
+fn format_program<'a>(
+    vm: &VM,
+    program: &'a [(String, usize, bool)],
+    mode: bool,
+    height: u16,
+    display_state: &mut DisplayState,
+    jmp: Option<(bool, uvm)>,
+) -> Text<'a> {
+    let height: usize = height.saturating_sub(4).into();
+    if !display_state.follow {
+        let cursor = display_state
+            .program_cursor
+            .min(program.len().saturating_sub(1));
+        display_state.program_cursor = cursor;
+        display_state.program_offset = display_state
+            .program_offset
+            .min(cursor)
+            .max((cursor + 1).saturating_sub(height));
+    } else if let Some(idx) = program
+        .iter()
+        .enumerate()
+        .find(|(_, (_, addr, _))| *addr == display_state.pc)
+        .map(|x| x.0)
+    {
+        display_state.program_cursor = idx;
+        display_state.program_offset = idx
+            .saturating_sub(height.saturating_sub(1) / 2)
+            .min(program.len().saturating_sub(height));
+    }
+
+    let mut lines = Vec::new();
+    let mut spans = Vec::new();
+
+    let load = Style::default().white().on_red();
+    let exec = Style::default().white().on_green();
+    let off = Style::default().black().on_dark_gray();
+
+    lines.push(Line::from(vec![
+        Span::raw(" "),
+        Span::styled("     LOAD     ", if mode { off } else { load }),
+        Span::styled("     EXEC     ", if mode { exec } else { off }),
+        Span::raw(" "),
+    ]));
+
+    lines.push(Line::raw(""));
+
+    let primary = Style::default().black().on_white();
+    let secondary = Style::default().black().on_dark_gray();
+    for (idx, (str, addr, stale)) in program
+        .iter()
+        .enumerate()
+        .skip(display_state.program_offset)
+    {
+        // Code modified since it was decoded
+        let mut address = if *stale {
+            Span::raw(format!("\n*{addr:08X}  ")).yellow()
+        } else {
+            Span::raw(format!("\n {addr:08X}  "))
+        };
+        if display_state.focus == Pane::Program && idx == display_state.program_cursor {
+            address = address.reversed();
+        }
+        match jmp {
+            _ if *addr == display_state.pc => {
+                spans.push(address);
+                spans.push(Span::styled(str, primary));
+            }
+            Some((rfl, val)) if rfl && vm.get_reg(val).is_err() => {
+                spans.push(address);
+                spans.push(Span::raw(str));
+            }
+            Some((rfl, val))
+                if *addr == if rfl { vm.get_reg(val).unwrap() } else { val } as usize =>
+            {
+                spans.push(address);
+                spans.push(Span::styled(str, secondary));
+            }
+            _ => {
+                spans.push(address);
+                spans.push(Span::raw(str));
+            }
+        }
+        lines.push(Line::from(spans));
+        spans = Vec::new();
+    }
+
+    Text::from(lines)
+}
+
+fn format_source(source: &[String], line: usize, height: u16) -> Text<'_> {
+    let height = usize::from(height.saturating_sub(2));
+    let offset = line
+        .saturating_sub(1 + height.saturating_sub(1) / 2)
+        .min(source.len().saturating_sub(height));
+
+    let current = Style::default().black().on_white();
+    let lines = source
+        .iter()
+        .enumerate()
+        .skip(offset)
+        .take(height)
+        .map(|(idx, str)| {
+            let number = Span::raw(format!(" {:>4}  ", idx + 1));
+            if idx + 1 == line {
+                Line::from(vec![number, Span::styled(str, current)])
+            } else {
+                Line::from(vec![number, Span::raw(str)])
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Text::from(lines)
+}
+
+fn format_regs<'a>(
+    regs: &[uvm],
+    (dst, src): (Vec<usize>, Vec<usize>),
+    edit: Option<&Edit>,
+    changes: &Changes,
+    format: Format,
+) -> Text<'a> {
+    let mut lines = Vec::new();
+
+    let primary_st = Style::default().black().on_white();
+    let secondary_st = Style::default().black().on_dark_gray();
+    let edit_st = Style::default().black().on_yellow();
+    let width = format.width(REG_LEN);
+    let mut spans = Vec::new();
+    for (idx, value) in regs.iter().enumerate() {
+        let name = Registers::register_name(idx as uvm);
+        let str = format!("{name} {}", format.format(*value, REG_LEN));
+        spans.push(Span::raw(if (idx as uvm).is_multiple_of(REGS_PER_ROW) {
+            " "
+        } else {
+            "  "
+        }));
+
+        spans.push(match edit {
+            Some(Edit::Registers {
+                idx: selected,
+                input,
+            }) if *selected as usize == idx => match input {
+                Some(input) => Span::styled(format!("{name} {input:_<width$}"), edit_st),
+                None => Span::styled(str, edit_st),
+            },
+            _ if dst.contains(&idx) => Span::styled(str, primary_st.patch(changes.reg_style(idx))),
+            _ if src.contains(&idx) => {
+                Span::styled(str, secondary_st.patch(changes.reg_style(idx)))
+            }
+            _ => Span::styled(str, changes.reg_style(idx)),
+        });
+
+        if idx as uvm % REGS_PER_ROW == REGS_PER_ROW - 1 {
+            lines.push(Line::from(spans));
+            spans = Vec::new();
+        }
+    }
+
+    lines.push(Line::from(spans));
+
+    Text::from(lines)
+}
+
+fn ram_title(display_state: &DisplayState, editing: bool) -> String {
+    if editing {
+        "RAM (hex, editing)".to_string()
+    } else {
+        format!(
+            "RAM ({}, {}-byte)",
+            display_state.ram_format.name(),
+            display_state.ram_group
+        )
+    }
+}
+
+/// Width of the RAM pane, including borders
+fn ram_width(display_state: &DisplayState) -> u16 {
+    let group = display_state.ram_group;
+    let cells = 16 / group * (display_state.ram_format.width(group) + 1);
+    (12 + cells + 1 + 16 + 2) as u16
+}
+
+fn format_ram<'a>(
+    ram: &[u8],
+    targets: &[(usize, bool)],
+    height: u16,
+    display_state: &mut DisplayState,
+    cursor: Option<(usize, Option<u8>)>,
+    changes: &Changes,
+) -> Text<'a> {
+    let height: usize = height.saturating_sub(3).into();
+    if let Some((addr, _)) = cursor {
+        let row = addr - addr % 16;
+        if row < display_state.ram_offset {
+            display_state.ram_offset = row;
+        } else if row >= display_state.ram_offset + height * 16 {
+            display_state.ram_offset = row.saturating_sub((height.saturating_sub(1)) * 16);
+        }
+    } else if let (true, Some((target, _))) = (display_state.follow, targets.first()) {
+        let mut offset = target.saturating_sub((height * 16) / 2);
+        offset = offset.saturating_sub(offset % 16);
+        display_state.ram_offset = offset;
+    }
+
+    // Memory is edited byte by byte
+    let (format, group) = if cursor.is_some() {
+        (Format::Hex, 1)
+    } else {
+        (display_state.ram_format, display_state.ram_group)
+    };
+    let width = format.width(group);
+
+    let mut lines = Vec::new();
+
+    lines.push(Line::raw(format!(
+        "            {}",
+        (0..16)
+            .step_by(group)
+            .map(|i| match format {
+                Format::Hex if group == 1 => format!("{i:02X}"),
+                _ => format!("{i:>width$X}"),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    )));
+
+    let write_style = Style::default().black().on_white();
+    let read_style = Style::default().black().on_dark_gray();
+    let found_style = Style::default().black().on_cyan();
+    let cursor_style = Style::default().black().on_yellow();
+    let style = |idx: usize| {
+        if let Some((addr, len)) = display_state.found {
+            if (addr..addr.saturating_add(len)).contains(&idx) {
+                return found_style;
+            }
+        }
+        let target = targets
+            .iter()
+            .find(|(addr, _)| (*addr..addr.saturating_add(REG_LEN)).contains(&idx));
+        match target {
+            Some((_, true)) => write_style,
+            Some((_, false)) => read_style,
+            None => Style::default(),
+        }
+        .patch(changes.ram_style(idx))
+    };
+
+    let rows = ram
+        .chunks(16)
+        .enumerate()
+        .skip(display_state.ram_offset / 16)
+        .take(height);
+    for (row, bytes) in rows {
+        let start = row * 16;
+        let mut spans = vec![Span::raw(format!(" {start:08X}   "))];
+        for (offset, cell) in bytes.chunks(group).enumerate() {
+            let idx = start + offset * group;
+            if offset > 0 {
+                spans.push(Span::raw(" "));
+            }
+            spans.push(match cursor {
+                Some((addr, Some(high))) if addr == idx => {
+                    Span::styled(format!("{high:X}_"), cursor_style)
+                }
+                Some((addr, None)) if addr == idx => {
+                    Span::styled(format.format(format::le_value(cell), group), cursor_style)
+                }
+                _ => {
+                    // Highlighted like the first highlighted byte of the value
+                    let cell_style = (idx..idx + group)
+                        .map(style)
+                        .find(|style| *style != Style::default())
+                        .unwrap_or_default();
+                    Span::styled(format.format(format::le_value(cell), group), cell_style)
+                }
+            });
+        }
+        spans.push(Span::raw("  "));
+        for (offset, byte) in bytes.iter().enumerate() {
+            spans.push(Span::styled(
+                format::ascii(*byte).to_string(),
+                style(start + offset),
+            ));
+        }
+        lines.push(Line::from(spans));
+    }
+
+    Text::from(lines)
+}
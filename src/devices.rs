@@ -0,0 +1,335 @@
+//! Devices mapped on the bus of every VM
+
+use crate::{
+    bus::{Device, Window},
+    uvm, REG_LEN,
+};
+
+/// Address of the instruction counter
+pub const CLOCK: uvm = 0x1_0000;
+/// Address of the random number generator
+pub const RANDOM: uvm = CLOCK + REG_LEN as uvm;
+/// Address of the timer
+pub const TIMER: uvm = RANDOM + REG_LEN as uvm;
+/// Address of the MMU
+pub const MMU: uvm = TIMER + REG_LEN as uvm;
+/// Address of the banked stack pointer, the SP of the mode not running
+pub const STACK: uvm = MMU + 2 * REG_LEN as uvm;
+/// Address of the interrupt vector table
+pub const VECTORS: uvm = 0x1_0100;
+/// Address of the trap handler, after the interrupt handlers
+pub const TRAP_HANDLER: uvm = VECTORS + INTERRUPT_LINES as uvm * REG_LEN as uvm;
+/// Address of the cause of the last trap
+pub const TRAP_CAUSE: uvm = TRAP_HANDLER + REG_LEN as uvm;
+/// Address of the value of the last trap, the operand of SYCALL
+pub const TRAP_VALUE: uvm = TRAP_CAUSE + REG_LEN as uvm;
+
+/// Number of interrupt lines, each with a handler in the vector table
+pub const INTERRUPT_LINES: u8 = 8;
+/// Interrupt line of the timer
+pub const TIMER_LINE: u8 = 0;
+
+const VECTORS_LEN: usize = (INTERRUPT_LINES as usize + 3) * REG_LEN;
+
+/// Name and length of the saved state of each standard device, in map order
+pub const STATES: &[(&str, usize)] = &[
+    ("clock", REG_LEN),
+    ("random", 0),
+    ("timer", 2 * REG_LEN),
+    ("mmu", 3 * REG_LEN),
+    ("vectors", VECTORS_LEN),
+];
+
+/// Devices and their addresses, the MMU controlling `window`
+pub fn standard(window: Window) -> Vec<(uvm, Box<dyn Device>)> {
+    vec![
+        (CLOCK, Box::new(Clock::default())),
+        (RANDOM, Box::new(Random)),
+        (TIMER, Box::new(Timer::default())),
+        (MMU, Box::new(Mmu { window, stack: 0 })),
+        (VECTORS, Box::new(Vectors::default())),
+    ]
+}
+
+/// Counts executed instructions as a 64 bit value, which can be overwritten
+#[derive(Default)]
+pub struct Clock {
+    ticks: uvm,
+}
+
+impl Device for Clock {
+    fn name(&self) -> &'static str {
+        "clock"
+    }
+
+    fn len(&self) -> uvm {
+        REG_LEN as uvm
+    }
+
+    fn read(&mut self, offset: uvm, buf: &mut [u8]) -> Result<(), String> {
+        read_word(self.ticks, offset, buf)
+    }
+
+    fn write(&mut self, offset: uvm, bytes: &[u8]) -> Result<(), String> {
+        self.ticks = write_word(self.ticks, offset, bytes)?;
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Option<u8> {
+        self.ticks = self.ticks.wrapping_add(1);
+        None
+    }
+
+    fn save(&self) -> Vec<u8> {
+        save_words(&[self.ticks])
+    }
+
+    fn state_len(&self) -> usize {
+        REG_LEN
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        [self.ticks] = restore_words(state);
+    }
+}
+
+/// Reads as random bytes, ignores writes
+pub struct Random;
+
+impl Device for Random {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn len(&self) -> uvm {
+        REG_LEN as uvm
+    }
+
+    fn read(&mut self, _offset: uvm, buf: &mut [u8]) -> Result<(), String> {
+        for byte in buf {
+            *byte = rand::random();
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, _offset: uvm, _bytes: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Raises its interrupt every N instructions, N being its 64 bit register.
+/// Writing the register restarts the count, 0 stops the timer.
+#[derive(Default)]
+pub struct Timer {
+    period: uvm,
+    ticks: uvm,
+}
+
+impl Device for Timer {
+    fn name(&self) -> &'static str {
+        "timer"
+    }
+
+    fn privileged(&self) -> bool {
+        true
+    }
+
+    fn len(&self) -> uvm {
+        REG_LEN as uvm
+    }
+
+    fn read(&mut self, offset: uvm, buf: &mut [u8]) -> Result<(), String> {
+        read_word(self.period, offset, buf)
+    }
+
+    fn write(&mut self, offset: uvm, bytes: &[u8]) -> Result<(), String> {
+        self.period = write_word(self.period, offset, bytes)?;
+        self.ticks = 0;
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Option<u8> {
+        if self.period == 0 {
+            return None;
+        }
+        self.ticks += 1;
+        (self.ticks >= self.period).then(|| {
+            self.ticks = 0;
+            TIMER_LINE
+        })
+    }
+
+    fn save(&self) -> Vec<u8> {
+        save_words(&[self.period, self.ticks])
+    }
+
+    fn state_len(&self) -> usize {
+        2 * REG_LEN
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        [self.period, self.ticks] = restore_words(state);
+    }
+}
+
+/// Start and length of the RAM accessible in user mode, as two 64 bit registers
+/// Then the banked stack pointer, exchanged with SP when switching modes.
+pub struct Mmu {
+    window: Window,
+    stack: uvm,
+}
+
+impl Mmu {
+    fn registers(&self) -> [uvm; 3] {
+        let (start, len) = self.window.get();
+        [start, len, self.stack]
+    }
+}
+
+impl Device for Mmu {
+    fn name(&self) -> &'static str {
+        "mmu"
+    }
+
+    fn privileged(&self) -> bool {
+        true
+    }
+
+    fn len(&self) -> uvm {
+        3 * REG_LEN as uvm
+    }
+
+    fn read(&mut self, offset: uvm, buf: &mut [u8]) -> Result<(), String> {
+        let (index, offset) = (offset / REG_LEN as uvm, offset % REG_LEN as uvm);
+        let word = self
+            .registers()
+            .get(index as usize)
+            .copied()
+            .ok_or("Access out of the device")?;
+        read_word(word, offset, buf)
+    }
+
+    fn write(&mut self, offset: uvm, bytes: &[u8]) -> Result<(), String> {
+        let (index, offset) = (offset / REG_LEN as uvm, offset % REG_LEN as uvm);
+        let mut registers = self.registers();
+        let word = registers
+            .get_mut(index as usize)
+            .ok_or("Access out of the device")?;
+        *word = write_word(*word, offset, bytes)?;
+        let [start, len, stack] = registers;
+        self.window.set((start, len));
+        self.stack = stack;
+        Ok(())
+    }
+
+    fn save(&self) -> Vec<u8> {
+        save_words(&self.registers())
+    }
+
+    fn state_len(&self) -> usize {
+        3 * REG_LEN
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        let [start, len, stack] = restore_words(state);
+        self.window.set((start, len));
+        self.stack = stack;
+    }
+}
+
+/// Address of the handler of each interrupt line and of traps, 0 for none,
+/// then the cause and value of the last trap
+pub struct Vectors {
+    handlers: [u8; VECTORS_LEN],
+}
+
+impl Default for Vectors {
+    fn default() -> Self {
+        Self {
+            handlers: [0; VECTORS_LEN],
+        }
+    }
+}
+
+impl Vectors {
+    fn get_mut(&mut self, offset: uvm, len: usize) -> Result<&mut [u8], String> {
+        let offset = offset as usize;
+        self.handlers
+            .get_mut(offset..offset + len)
+            .ok_or_else(|| "Access out of the device".to_string())
+    }
+}
+
+impl Device for Vectors {
+    fn name(&self) -> &'static str {
+        "vectors"
+    }
+
+    fn privileged(&self) -> bool {
+        true
+    }
+
+    fn len(&self) -> uvm {
+        self.handlers.len() as uvm
+    }
+
+    fn read(&mut self, offset: uvm, buf: &mut [u8]) -> Result<(), String> {
+        buf.copy_from_slice(self.get_mut(offset, buf.len())?);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: uvm, bytes: &[u8]) -> Result<(), String> {
+        self.get_mut(offset, bytes.len())?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.handlers.to_vec()
+    }
+
+    fn state_len(&self) -> usize {
+        VECTORS_LEN
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        for (byte, saved) in self.handlers.iter_mut().zip(state) {
+            *byte = *saved;
+        }
+    }
+}
+
+/// Reads bytes of a little-endian register
+fn read_word(value: uvm, offset: uvm, buf: &mut [u8]) -> Result<(), String> {
+    let bytes = value.to_le_bytes();
+    let offset = offset as usize;
+    buf.copy_from_slice(
+        bytes
+            .get(offset..offset + buf.len())
+            .ok_or("Access out of the device")?,
+    );
+    Ok(())
+}
+
+/// Writes bytes of a little-endian register, returning its new value
+fn write_word(value: uvm, offset: uvm, bytes: &[u8]) -> Result<uvm, String> {
+    let mut word = value.to_le_bytes();
+    let offset = offset as usize;
+    word.get_mut(offset..offset + bytes.len())
+        .ok_or("Access out of the device")?
+        .copy_from_slice(bytes);
+    Ok(uvm::from_le_bytes(word))
+}
+
+/// State made of 64 bit values
+fn save_words(words: &[uvm]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// Values saved by `save_words`
+fn restore_words<const N: usize>(state: &[u8]) -> [uvm; N] {
+    let mut words = [0; N];
+    for (word, bytes) in words.iter_mut().zip(state.chunks_exact(REG_LEN)) {
+        *word = uvm::from_le_bytes(bytes.try_into().unwrap_or_default());
+    }
+    words
+}
@@ -9,6 +9,37 @@ pub struct Instruction {
     pub val: uvm,
 }
 
+/// What PRINT and EPRINT write, selected by their register byte
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Print {
+    /// The bytes of the value up to the first NUL
+    Word,
+    /// The N low bytes of the value, NULs included
+    Bytes(usize),
+    /// The NUL-terminated string at the address in the value
+    String,
+    /// The value in decimal
+    Unsigned,
+    /// The value in decimal, as a two's complement
+    Signed,
+    /// The value in hexadecimal
+    Hex,
+}
+
+impl Print {
+    pub fn from_reg(reg: u8) -> Option<Self> {
+        match reg {
+            0 => Some(Self::Word),
+            n if usize::from(n) <= REG_LEN => Some(Self::Bytes(n.into())),
+            0x10 => Some(Self::String),
+            0x11 => Some(Self::Unsigned),
+            0x12 => Some(Self::Signed),
+            0x13 => Some(Self::Hex),
+            _ => None,
+        }
+    }
+}
+
 #[allow(clippy::too_many_lines)]
 #[allow(clippy::manual_range_patterns)]
 impl Instruction {
@@ -108,6 +139,7 @@ impl Instruction {
             | opc!(JLE)
             | opc!(PRINT)
             | opc!(EPRINT)
+            | opc!(DUMP)
                 if *rfl =>
             {
                 src.push(val);
@@ -133,26 +165,42 @@ impl Instruction {
             dst.push(reg_index!(rr));
             src.push(reg_index!(lr));
         }
+        if let opc!(IRET) = opc {
+            dst.push(reg_index!(pc));
+            dst.push(reg_index!(sr));
+        }
         (dst, src)
     }
 
-    pub fn target_ram(&self) -> Vec<(bool, uvm, bool)> {
+    /// Memory accessed by the instruction as `(rfl, val, offset, write)`: the
+    /// address is `val`, or the value of register `val` before execution, plus
+    /// `offset`
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
+    pub fn target_ram(&self) -> Vec<(bool, uvm, i64, bool)> {
         match self.opc {
-            opc!(LOAD) => vec![(self.rfl, self.val, false)],
+            opc!(LOAD) => vec![(self.rfl, self.val, 0, false)],
             opc!(STOREB) | opc!(STOREH) | opc!(STOREW) | opc!(STORED) => {
-                vec![(true, self.reg.into(), true)]
+                vec![(true, self.reg.into(), 0, true)]
+            }
+            opc!(PUSH) => vec![(true, reg_index!(sp), 0, true)],
+            opc!(DUP) => vec![
+                (true, reg_index!(sp), 0, true),
+                (self.rfl, self.val, 0, false),
+            ],
+            opc!(POP) | opc!(DROP) => vec![(true, reg_index!(sp), -8, false)],
+            opc!(IRET) => vec![
+                (true, reg_index!(sp), -16, false),
+                (true, reg_index!(sp), -8, false),
+            ],
+            opc!(PRINT) | opc!(EPRINT) if Print::from_reg(self.reg) == Some(Print::String) => {
+                vec![(self.rfl, self.val, 0, false)]
             }
-            opc!(PUSH) => vec![(true, reg_index!(sp), true)],
-            opc!(DUP) => vec![(true, reg_index!(sp), true), (self.rfl, self.val, false)],
-            opc!(POP) | opc!(DROP) => vec![(
-                true,
-                uvm::saturating_sub(reg_index!(sp), REG_LEN as uvm),
-                true,
-            )],
+            opc!(DUMP) if self.reg > 0 => vec![(self.rfl, self.val, 0, false)],
             _ => vec![],
         }
     }
 
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
     pub fn target_addr(&self) -> Option<(bool, uvm)> {
         match self.opc {
             opc!(CALL)
@@ -172,6 +220,16 @@ impl Instruction {
 impl Debug for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let Self { rfl, opc, reg, val } = self;
+        let print = |name: &str| match Print::from_reg(*reg) {
+            Some(Print::Word) => Some(name.to_string()),
+            Some(Print::Bytes(len)) => Some(format!("{name}B {len}")),
+            Some(Print::String) => Some(format!("{name}S")),
+            Some(Print::Unsigned) => Some(format!("{name}U")),
+            Some(Print::Signed) => Some(format!("{name}I")),
+            Some(Print::Hex) => Some(format!("{name}X")),
+            None => None,
+        };
+        let len = *reg;
         let reg = Registers::register_name((*reg).into());
         let val = if *rfl {
             format!("{:<REG_LEN$}", Registers::register_name(*val as uvm))
@@ -182,12 +240,18 @@ impl Debug for Instruction {
         match opc {
             opc!(NOP) => write!(f, "NOP           "),
             opc!(HALT) => write!(f, "HALT      {val}"),
+            opc!(SYCALL) => write!(f, "SYCALL    {val}"),
+            opc!(CLEAR) => write!(f, "CLEAR  {reg}         "),
             opc!(SET) => write!(f, "SET    {reg} {val}"),
             opc!(LOAD) => write!(f, "LOAD   {reg} {val}"),
             opc!(STOREB) => write!(f, "STOREB  {reg} {val}"),
             opc!(STOREH) => write!(f, "STOREH  {reg} {val}"),
             opc!(STOREW) => write!(f, "STOREW  {reg} {val}"),
             opc!(STORED) => write!(f, "STORED  {reg} {val}"),
+            opc!(SWAP) => write!(f, "SWAP   {reg} {val}"),
+            opc!(NEG) => write!(f, "NEG    {reg}         "),
+            opc!(INC) => write!(f, "INC    {reg}         "),
+            opc!(DEC) => write!(f, "DEC    {reg}         "),
             opc!(ADD) => write!(f, "ADD    {reg} {val}"),
             opc!(SUB) => write!(f, "SUB    {reg} {val}"),
             opc!(MUL) => write!(f, "MUL    {reg} {val}"),
@@ -195,14 +259,26 @@ impl Debug for Instruction {
             opc!(MOD) => write!(f, "MOD    {reg} {val}"),
             opc!(AND) => write!(f, "AND    {reg} {val}"),
             opc!(PUSH) => write!(f, "PUSH      {val}"),
+            opc!(DUP) => write!(f, "DUP       {val}"),
             opc!(POP) => write!(f, "POP    {reg}         "),
             opc!(DROP) => write!(f, "DROP              "),
             opc!(CALL) => write!(f, "CALL      {val}"),
             opc!(RET) => write!(f, "RET       {val}"),
+            opc!(IRET) => write!(f, "IRET              "),
             opc!(JMP) => write!(f, "JMP       {val}"),
             opc!(JEQ) => write!(f, "JEQ    {reg} {val}"),
             opc!(JNE) => write!(f, "JNE    {reg} {val}"),
-            opc!(PRINT) => write!(f, "PRINT     {val}"),
+            opc!(PRINT) | opc!(EPRINT) => {
+                match print(if *opc == opc!(PRINT) {
+                    "PRINT"
+                } else {
+                    "EPRINT"
+                }) {
+                    Some(name) => write!(f, "{name:<10}{val}"),
+                    None => write!(f, "INVALID"),
+                }
+            }
+            opc!(DUMP) => write!(f, "{:<10}{val}", format!("DUMP {len}")),
             _ => write!(f, "INVALID"),
         }?;
         Ok(())
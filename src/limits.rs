@@ -0,0 +1,103 @@
+//! Resource limits, so that untrusted programs cannot run or print forever.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Resource a run can exhaust
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// Instructions executed
+    Fuel,
+    /// Wall-clock time
+    Time,
+    /// Bytes of guest output
+    Output,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fuel => "instruction limit",
+            Self::Time => "time limit",
+            Self::Output => "output limit",
+        })
+    }
+}
+
+/// Amount of each resource a run may use, `None` is unlimited
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub fuel: Option<u64>,
+    pub time: Option<Duration>,
+    pub output: Option<u64>,
+}
+
+/// What is left of the limits of a VM
+#[derive(Default)]
+pub struct Budget {
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    output: Option<u64>,
+    /// Output was dropped for lack of budget
+    overflowed: bool,
+}
+
+impl Budget {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            fuel: limits.fuel,
+            deadline: limits.time.map(|time| Instant::now() + time),
+            output: limits.output,
+            overflowed: false,
+        }
+    }
+
+    /// Adds to each limited resource. The time limit restarts from now.
+    pub fn refuel(&mut self, limits: Limits) {
+        if let (Some(fuel), Some(more)) = (&mut self.fuel, limits.fuel) {
+            *fuel = fuel.saturating_add(more);
+        }
+        if let (Some(deadline), Some(more)) = (&mut self.deadline, limits.time) {
+            *deadline = Instant::now() + more;
+        }
+        if let (Some(output), Some(more)) = (&mut self.output, limits.output) {
+            *output = output.saturating_add(more);
+            self.overflowed = false;
+        }
+    }
+
+    /// First resource that is used up, if any
+    pub fn exhausted(&self) -> Option<Resource> {
+        if self.fuel == Some(0) {
+            Some(Resource::Fuel)
+        } else if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Some(Resource::Time)
+        } else if self.overflowed {
+            Some(Resource::Output)
+        } else {
+            None
+        }
+    }
+
+    pub fn spend_fuel(&mut self) {
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.saturating_sub(1);
+        }
+    }
+
+    /// Spends up to `len` bytes of output, returning how many may be written
+    pub fn spend_output(&mut self, len: usize) -> usize {
+        let Some(output) = &mut self.output else {
+            return len;
+        };
+        let allowed = usize::try_from(*output).map_or(len, |output| len.min(output));
+        *output -= allowed as u64;
+        self.overflowed |= allowed < len;
+        allowed
+    }
+}
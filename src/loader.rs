@@ -2,10 +2,7 @@ use crate::{instruction::Instruction, uvm, REG_LEN};
 use std::slice::Iter;
 
 pub fn decode(bytes: &[u8], address: usize) -> Option<Instruction> {
-    let mut bytes = bytes
-        .get(address..)
-        .unwrap_or_else(|| panic!("Read out of memory at 0x{address:X}"))
-        .iter();
+    let mut bytes = bytes.get(address..)?.iter();
     if let Some(instruction) = collect_instruction(&mut bytes) {
         return Some(instruction);
     }
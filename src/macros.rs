@@ -9,16 +9,16 @@ macro_rules! reg_index {
     (bp) => {
         2
     };
-    (rr) => {
+    (lr) => {
         3
     };
-    (sr) => {
+    (rr) => {
         4
     };
-    (fr) => {
+    (sr) => {
         5
     };
-    (lr) => {
+    (fr) => {
         6
     };
     (r0) => {
@@ -193,4 +193,7 @@ macro_rules! opc {
     (DUMP) => {
         0x2F
     };
+    (IRET) => {
+        0x30
+    };
 }
@@ -1,20 +1,32 @@
-#![warn(
-    clippy::pedantic,
-    clippy::missing_panics_doc,
-    clippy::indexing_slicing
-)]
+#![warn(clippy::pedantic, clippy::missing_panics_doc, clippy::indexing_slicing)]
 #![allow(clippy::cast_possible_truncation, clippy::unreadable_literal)]
 
-use clap::Parser;
-use std::{fs, path::PathBuf};
+use clap::{Parser, Subcommand};
+use debug_info::DebugInfo;
+use limits::Limits;
+use snapshot::Snapshot;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    rc::Rc,
+    time::Duration,
+};
 
 #[cfg(feature = "debugger")]
 mod debugger;
 
+mod bus;
+mod coredump;
+mod debug_info;
+mod devices;
 mod instruction;
+mod limits;
 mod loader;
 mod macros;
 mod registers;
+mod snapshot;
+mod trap;
 mod vm;
 
 #[allow(non_camel_case_types)]
@@ -22,29 +34,236 @@ type uvm = u64;
 
 const REG_LEN: usize = uvm::BITS as usize / 8;
 
+/// Highest exit code of the program passed through, larger ones are clamped
+const EXIT_MAX: u8 = 123;
+/// The program was stopped by a resource limit, as with timeout(1)
+const EXIT_EXHAUSTED: u8 = 124;
+/// The program faulted
+const EXIT_FAULT: u8 = 125;
+/// A file could not be read or is invalid
+const EXIT_INVALID: u8 = 126;
+
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Sets a custom target file
-    #[arg(short, long, value_name = "FILE")]
-    file: PathBuf,
+    #[arg(short, long, value_name = "FILE", required = true)]
+    file: Option<PathBuf>,
 
     /// Sets a custom config file
     #[arg(short, long)]
     debug: bool,
+
+    /// Serves the program to a gdb client on a local TCP port or unix socket
+    #[arg(long, value_name = "PORT|SOCKET")]
+    gdb: Option<String>,
+
+    /// Runs debugger commands from a file without a terminal, failing when a
+    /// command or an assertion fails
+    #[arg(long, value_name = "FILE")]
+    script: Option<PathBuf>,
+
+    /// Writes the transcript of --script to a file instead of stdout
+    #[arg(long, value_name = "FILE", requires = "script")]
+    transcript: Option<PathBuf>,
+
+    /// Sets a custom debug info file, defaults to FILE with a .dbg extension
+    #[arg(short = 'g', long, value_name = "FILE")]
+    debug_info: Option<PathBuf>,
+
+    /// Saves a snapshot to FILE with a .snap extension once N instructions
+    /// have executed
+    #[arg(long, value_name = "N", conflicts_with_all = ["debug", "gdb", "script"])]
+    snapshot_at: Option<u64>,
+
+    /// Starts from a snapshot of FILE instead of its beginning
+    #[arg(long, value_name = "SNAPSHOT")]
+    restore: Option<PathBuf>,
+
+    /// Stops the program after N instructions
+    #[arg(long, value_name = "N", conflicts_with_all = ["debug", "gdb"])]
+    max_instructions: Option<u64>,
+
+    /// Stops the program after SECONDS of wall-clock time
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds, conflicts_with_all = ["debug", "gdb"])]
+    timeout: Option<Duration>,
+
+    /// Stops the program once it outputs more than BYTES
+    #[arg(long, value_name = "BYTES", conflicts_with_all = ["debug", "gdb", "script"])]
+    max_output: Option<u64>,
+
+    /// Does not report how the program ended, the exit code still tells
+    #[arg(short, long, conflicts_with_all = ["debug", "gdb", "script"])]
+    quiet: bool,
+}
+
+fn parse_seconds(arg: &str) -> Result<Duration, String> {
+    arg.parse()
+        .map_err(|err| format!("{err}"))
+        .and_then(|secs| Duration::try_from_secs_f64(secs).map_err(|err| format!("{err}")))
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Debugs programs from an editor through the Debug Adapter Protocol on stdio
+    Dap,
+    /// Inspects the state of a faulted program in the TUI debugger, read-only
+    Debug {
+        /// Core dump written by the fault, its debug info defaults to FILE
+        /// with a .dbg extension
+        #[arg(long, value_name = "FILE")]
+        core: PathBuf,
+    },
 }
 
-fn main() -> Result<(), std::io::Error> {
+fn main() -> ExitCode {
     let args = Args::parse();
-    let program = fs::read(args.file)?;
+    match args.command {
+        Some(command) => run_command(command),
+        None => run(args),
+    }
+}
+
+/// Reports an error, returning the exit code
+fn fail(err: &io::Error, exit_code: u8) -> ExitCode {
+    eprintln!("Error: {err}");
+    ExitCode::from(exit_code)
+}
+
+fn run_command(command: Command) -> ExitCode {
+    match command {
+        Command::Dap => {
+            #[cfg(feature = "debugger")]
+            if let Err(err) = debugger::dap::serve() {
+                return fail(&err, 1);
+            }
+            #[cfg(not(feature = "debugger"))]
+            println!("Debugger not included in this build");
+        }
+        Command::Debug { core } => {
+            #[cfg(feature = "tui")]
+            {
+                let loaded = DebugInfo::find(&core).and_then(|debug_info| {
+                    Ok((coredump::CoreDump::load(&core)?, debug_info.map(Rc::new)))
+                });
+                let (core, debug_info) = match loaded {
+                    Ok(loaded) => loaded,
+                    Err(err) => return fail(&err, EXIT_INVALID),
+                };
+                if let Err(err) = debugger::post_mortem(core, debug_info.as_ref()) {
+                    return fail(&err, 1);
+                }
+            }
+            #[cfg(not(feature = "tui"))]
+            println!(
+                "TUI debugger not included in this build ({})",
+                core.display()
+            );
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Program, its debug info and the snapshot to start from
+type Loaded = (Vec<u8>, Option<Rc<DebugInfo>>, Option<Snapshot>);
 
-    if args.debug {
+fn load(file: &Path, debug_info: Option<&Path>, restore: Option<&Path>) -> io::Result<Loaded> {
+    let program = fs::read(file)?;
+    let debug_info = if let Some(path) = debug_info {
+        Some(DebugInfo::load(path)?)
+    } else {
+        DebugInfo::find(file)?
+    }
+    .map(Rc::new);
+    let start = restore.map(Snapshot::load).transpose()?;
+    Ok((program, debug_info, start))
+}
+
+fn run(args: Args) -> ExitCode {
+    let file = args.file.expect("FILE is required");
+    let (program, debug_info, start) =
+        match load(&file, args.debug_info.as_deref(), args.restore.as_deref()) {
+            Ok(loaded) => loaded,
+            Err(err) => return fail(&err, EXIT_INVALID),
+        };
+
+    let limits = Limits {
+        fuel: args.max_instructions,
+        time: args.timeout,
+        output: args.max_output,
+    };
+
+    if let Some(script) = args.script {
+        #[cfg(feature = "debugger")]
+        {
+            let passed = match args.transcript {
+                Some(path) => fs::File::create(path).and_then(|transcript| {
+                    debugger::script::run(
+                        &program,
+                        debug_info.as_ref(),
+                        start,
+                        limits,
+                        &script,
+                        transcript,
+                    )
+                }),
+                None => debugger::script::run(
+                    &program,
+                    debug_info.as_ref(),
+                    start,
+                    limits,
+                    &script,
+                    io::stdout(),
+                ),
+            };
+            match passed {
+                Ok(true) => (),
+                Ok(false) => return ExitCode::FAILURE,
+                Err(err) => return fail(&err, 1),
+            }
+        }
+        #[cfg(not(feature = "debugger"))]
+        println!("Debugger not included in this build ({})", script.display());
+    } else if let Some(address) = args.gdb {
         #[cfg(feature = "debugger")]
-        debugger::run(&program)?;
+        if let Err(err) = debugger::gdb::serve(&program, debug_info.as_ref(), start, &address) {
+            return fail(&err, 1);
+        }
         #[cfg(not(feature = "debugger"))]
-        println!("Debugger not included in this build");
+        println!("Debugger not included in this build ({address})");
+    } else if args.debug {
+        #[cfg(feature = "tui")]
+        if let Err(err) = debugger::run(&program, debug_info.as_ref(), start) {
+            return fail(&err, 1);
+        }
+        #[cfg(not(feature = "tui"))]
+        println!("TUI debugger not included in this build");
     } else {
-        vm::run(&program);
+        let snapshot_path = file.with_extension("snap");
+        let snapshot_at = args
+            .snapshot_at
+            .map(|cycles| (cycles, snapshot_path.as_path()));
+        let outcome = vm::run(
+            &program,
+            debug_info,
+            start.as_ref(),
+            limits,
+            snapshot_at,
+            &file.with_extension("core"),
+            args.quiet,
+        );
+        return match outcome {
+            Ok(vm::Outcome::Exited(exit_code)) => {
+                ExitCode::from(u8::try_from(exit_code).map_or(EXIT_MAX, |code| code.min(EXIT_MAX)))
+            }
+            Ok(vm::Outcome::ResourceExhausted { .. }) => ExitCode::from(EXIT_EXHAUSTED),
+            Ok(vm::Outcome::Stopped) => ExitCode::SUCCESS,
+            Err(err) => fail(&err, EXIT_FAULT),
+        };
     }
 
-    Ok(())
+    ExitCode::SUCCESS
 }
@@ -1,4 +1,13 @@
-use crate::uvm;
+use crate::{trap::Fault, uvm};
+
+pub const REG_COUNT: uvm = 15;
+
+/// SR bit enabling interrupts
+pub const SR_INTERRUPTS: uvm = 1;
+/// Position in SR of the mask of enabled interrupt lines, one bit per line
+pub const SR_LINES: u32 = 8;
+/// SR bit of user mode, supervisor mode when clear
+pub const SR_USER: uvm = 2;
 
 #[allow(unused)]
 #[derive(Default, Debug)]
@@ -21,7 +30,7 @@ pub struct Registers {
 }
 
 impl Registers {
-    pub fn get(&self, reg_idx: uvm) -> Result<uvm, String> {
+    pub fn get(&self, reg_idx: uvm) -> Result<uvm, Fault> {
         Ok(match reg_idx {
             0 => self.pc,
             1 => self.sp,
@@ -38,11 +47,11 @@ impl Registers {
             12 => self.r5,
             13 => self.r6,
             14 => self.r7,
-            _ => return Err(format!("Invalid read {reg_idx}")),
+            _ => return Err(Fault::InvalidRead(reg_idx)),
         })
     }
 
-    pub fn set(&mut self, reg_idx: uvm, value: uvm) -> Result<(), String> {
+    pub fn set(&mut self, reg_idx: uvm, value: uvm) -> Result<(), Fault> {
         match reg_idx {
             0 => self.pc = value,
             1 => self.sp = value,
@@ -59,18 +68,47 @@ impl Registers {
             12 => self.r5 = value,
             13 => self.r6 = value,
             14 => self.r7 = value,
-            _ => return Err(format!("Invalid write on register index {reg_idx}")),
+            _ => return Err(Fault::InvalidWrite(reg_idx)),
         }
         Ok(())
     }
 
-    pub fn show(&self) -> Result<Vec<String>, String> {
-        (0..15)
-            .map(|i| {
-                self.get(i)
-                    .map(|reg| format!("{} {:08X}", Self::register_name(i), reg))
-            })
-            .collect()
+    /// Values of all registers, in index order
+    pub fn values(&self) -> [uvm; REG_COUNT as usize] {
+        [
+            self.pc, self.sp, self.bp, self.lr, self.rr, self.sr, self.fr, self.r0, self.r1,
+            self.r2, self.r3, self.r4, self.r5, self.r6, self.r7,
+        ]
+    }
+
+    /// Registers holding `values`, in index order
+    pub fn from_values(values: [uvm; REG_COUNT as usize]) -> Self {
+        let [pc, sp, bp, lr, rr, sr, fr, r0, r1, r2, r3, r4, r5, r6, r7] = values;
+        Self {
+            pc,
+            sp,
+            bp,
+            lr,
+            rr,
+            sr,
+            fr,
+            r0,
+            r1,
+            r2,
+            r3,
+            r4,
+            r5,
+            r6,
+            r7,
+        }
+    }
+
+    /// Index of a register from its name, case insensitive and optionally
+    /// prefixed with `$`
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn register_index(name: &str) -> Option<uvm> {
+        let name = name.strip_prefix('$').unwrap_or(name);
+        (0..REG_COUNT).find(|idx| Self::register_name(*idx).eq_ignore_ascii_case(name))
     }
 
     pub fn register_name(reg_idx: uvm) -> String {
@@ -0,0 +1,273 @@
+//! Snapshot of the full VM state, to resume a run later or elsewhere.
+//!
+//! The file is binary, all numbers are little-endian `u64` unless noted:
+//!
+//! ```text
+//! magic    "VMSNAP"
+//! version  u16
+//! cycles   instructions executed so far
+//! regs     one value per register, in index order
+//! frames   count, then call site, function and BP of each call in progress,
+//!          outermost first
+//! ram      length, then the bytes
+//! pending  mask of the interrupt lines raised and not handled yet
+//! devices  count, then the name and state of each device, by address, each
+//!          as a length and the bytes
+//! ```
+//!
+//! Files with another version, or whose devices differ from the standard ones,
+//! are rejected rather than guessed at.
+
+use crate::{
+    devices,
+    registers::REG_COUNT,
+    uvm,
+    vm::{Frame, RAM_LEN},
+    REG_LEN,
+};
+use std::{fs, io, path::Path};
+
+const MAGIC: &[u8] = b"VMSNAP";
+const VERSION: u16 = 1;
+
+pub struct Snapshot {
+    pub cycles: u64,
+    pub registers: [uvm; REG_COUNT as usize],
+    pub frames: Vec<Frame>,
+    pub ram: Vec<u8>,
+    pub pending: u64,
+    /// Name and state of each device
+    pub devices: Vec<(String, Vec<u8>)>,
+}
+
+impl Snapshot {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::decode(&fs::read(path)?).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid snapshot : {err}"),
+            )
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.encode())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(self.cycles.to_le_bytes());
+        for value in self.registers {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.extend((self.frames.len() as u64).to_le_bytes());
+        for frame in &self.frames {
+            for value in [frame.call_site, frame.function, frame.bp] {
+                bytes.extend(value.to_le_bytes());
+            }
+        }
+        push_section(&mut bytes, &self.ram);
+        bytes.extend(self.pending.to_le_bytes());
+        bytes.extend((self.devices.len() as u64).to_le_bytes());
+        for (name, state) in &self.devices {
+            push_section(&mut bytes, name.as_bytes());
+            push_section(&mut bytes, state);
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader::new(bytes, MAGIC, VERSION)?;
+        let cycles = reader.u64()?;
+        let mut registers = [0; REG_COUNT as usize];
+        for value in &mut registers {
+            *value = reader.u64()?;
+        }
+        let frames = (0..reader.u64()?)
+            .map(|_| {
+                Ok(Frame {
+                    call_site: reader.u64()?,
+                    function: reader.u64()?,
+                    bp: reader.u64()?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let ram = reader.section()?.to_vec();
+        if ram.len() != RAM_LEN {
+            return Err(format!("{} bytes of RAM, expected {RAM_LEN}", ram.len()));
+        }
+        let pending = reader.u64()?;
+        let devices = (0..reader.u64()?)
+            .map(|_| Ok((reader.string()?, reader.section()?.to_vec())))
+            .collect::<Result<Vec<_>, String>>()?;
+        check_devices(&devices)?;
+        reader.finish()?;
+        Ok(Self {
+            cycles,
+            registers,
+            frames,
+            ram,
+            pending,
+            devices,
+        })
+    }
+}
+
+/// Checks that `states` are those of the standard devices
+fn check_devices(states: &[(String, Vec<u8>)]) -> Result<(), String> {
+    if states.len() != devices::STATES.len() {
+        return Err(format!(
+            "{} devices, expected {}",
+            states.len(),
+            devices::STATES.len()
+        ));
+    }
+    for ((name, state), (expected, len)) in states.iter().zip(devices::STATES) {
+        if name != expected {
+            return Err(format!("device {name}, expected {expected}"));
+        }
+        if state.len() != *len {
+            return Err(format!(
+                "{name} : {} bytes of state, expected {len}",
+                state.len()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Appends the length of `section`, then its bytes
+pub fn push_section(bytes: &mut Vec<u8>, section: &[u8]) {
+    bytes.extend((section.len() as u64).to_le_bytes());
+    bytes.extend(section);
+}
+
+/// Reads the fields of a file, each read failing once the file is exhausted
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Checks the magic and version at the start of the file
+    pub fn new(bytes: &'a [u8], magic: &[u8], version: u16) -> Result<Self, String> {
+        let mut reader = Self { bytes };
+        if reader.take(magic.len())? != magic {
+            return Err("wrong file type".to_string());
+        }
+        let found = u16::from_le_bytes(reader.array()?);
+        if found != version {
+            return Err(format!("unsupported version {found}, expected {version}"));
+        }
+        Ok(reader)
+    }
+
+    /// Checks that the whole file was read
+    pub fn finish(&self) -> Result<(), String> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err("trailing bytes".to_string())
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.bytes.len() {
+            return Err("truncated".to_string());
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.array::<REG_LEN>()?))
+    }
+
+    pub fn section(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u64()?;
+        self.take(usize::try_from(len).map_err(|_| "truncated")?)
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.section()?.to_vec()).map_err(|_| "invalid text".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    fn snapshot() -> Vec<u8> {
+        let mut vm = VM::new();
+        vm.load(&[0x80, 0, 0]);
+        vm.snapshot().encode()
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = snapshot();
+        let snapshot = Snapshot::decode(&bytes).unwrap();
+        assert_eq!(snapshot.ram.get(..3), Some(&[0x80, 0, 0][..]));
+        assert_eq!(snapshot.encode(), bytes);
+    }
+
+    #[test]
+    fn header() {
+        let mut bytes = snapshot();
+        bytes.splice(..1, *b"X");
+        assert_eq!(Snapshot::decode(&bytes).err().unwrap(), "wrong file type");
+
+        let mut bytes = snapshot();
+        bytes.splice(MAGIC.len()..MAGIC.len() + 2, 2u16.to_le_bytes());
+        assert_eq!(
+            Snapshot::decode(&bytes).err().unwrap(),
+            "unsupported version 2, expected 1"
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = snapshot();
+        for len in 0..bytes.len() {
+            assert!(Snapshot::decode(bytes.get(..len).unwrap()).is_err());
+        }
+
+        let mut bytes = snapshot();
+        bytes.push(0);
+        assert_eq!(Snapshot::decode(&bytes).err().unwrap(), "trailing bytes");
+    }
+
+    #[test]
+    fn devices() {
+        let mut snapshot = Snapshot::decode(&self::snapshot()).unwrap();
+        snapshot.devices.pop();
+        assert_eq!(
+            Snapshot::decode(&snapshot.encode()).err().unwrap(),
+            "4 devices, expected 5"
+        );
+
+        let mut snapshot = Snapshot::decode(&self::snapshot()).unwrap();
+        snapshot.devices.swap(0, 1);
+        assert_eq!(
+            Snapshot::decode(&snapshot.encode()).err().unwrap(),
+            "device random, expected clock"
+        );
+
+        let mut snapshot = Snapshot::decode(&self::snapshot()).unwrap();
+        if let Some((_, state)) = snapshot.devices.get_mut(2) {
+            state.push(0);
+        }
+        assert_eq!(
+            Snapshot::decode(&snapshot.encode()).err().unwrap(),
+            "timer : 17 bytes of state, expected 16"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Faults of instructions, and the causes a guest handler can trap
+
+use crate::{
+    bus::{REOM, WEOM},
+    uvm,
+};
+use std::fmt;
+
+/// Why an instruction faulted, as written to the trap cause register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+    /// Unknown opcode, undecodable bytes, or an operand the instruction rejects
+    IllegalInstruction = 1,
+    /// Register index out of range
+    InvalidRegister = 2,
+    /// Read outside of RAM and the devices
+    ReadOutOfMemory = 3,
+    /// Write outside of RAM and the devices
+    WriteOutOfMemory = 4,
+    /// DIV or MOD by zero
+    DivisionByZero = 5,
+    /// SYCALL, with its operand as the trap value
+    SystemCall = 6,
+    /// Supervisor-only instruction, register or memory used in user mode
+    PrivilegeViolation = 7,
+}
+
+impl Cause {
+    pub fn code(self) -> uvm {
+        self as uvm
+    }
+}
+
+/// Error of an instruction, which ends the run unless it is trapped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// No decodable instruction at this address
+    NoInstruction(uvm),
+    UnexpectedOpcode(u8),
+    /// An operand the instruction rejects
+    InvalidOperand(String),
+    /// Read of this register index
+    InvalidRead(uvm),
+    /// Write of this register index
+    InvalidWrite(uvm),
+    ReadOutOfMemory(uvm),
+    WriteOutOfMemory(uvm),
+    /// A device rejected an access at this address
+    Device {
+        addr: uvm,
+        write: bool,
+        reason: String,
+    },
+    DivisionByZero,
+    /// User mode accessed this address, outside of its window or in a
+    /// privileged device
+    PrivilegedAccess(uvm),
+    /// User mode executed this supervisor-only opcode
+    PrivilegedInstruction(u8),
+    /// SYCALL with this operand, without a trap handler
+    UnhandledSystemCall(uvm),
+    /// Guest output could not be written
+    Output(String),
+}
+
+impl Fault {
+    /// Cause given to the trap handler, `None` when the fault is not the
+    /// guest's to handle
+    pub fn cause(&self) -> Option<Cause> {
+        Some(match self {
+            Self::NoInstruction(_) | Self::UnexpectedOpcode(_) | Self::InvalidOperand(_) => {
+                Cause::IllegalInstruction
+            }
+            Self::InvalidRead(_) | Self::InvalidWrite(_) => Cause::InvalidRegister,
+            Self::ReadOutOfMemory(_) | Self::Device { write: false, .. } => Cause::ReadOutOfMemory,
+            Self::WriteOutOfMemory(_) | Self::Device { write: true, .. } => Cause::WriteOutOfMemory,
+            Self::DivisionByZero => Cause::DivisionByZero,
+            Self::PrivilegedAccess(_) | Self::PrivilegedInstruction(_) => Cause::PrivilegeViolation,
+            Self::UnhandledSystemCall(_) | Self::Output(_) => return None,
+        })
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoInstruction(addr) => write!(f, "No instruction at 0x{addr:X}"),
+            Self::UnexpectedOpcode(opc) => write!(f, "Unexpected opcode 0x{opc:02X}"),
+            Self::InvalidOperand(reason) | Self::Output(reason) => f.write_str(reason),
+            Self::InvalidRead(idx) => write!(f, "Invalid read {idx}"),
+            Self::InvalidWrite(idx) => write!(f, "Invalid write on register index {idx}"),
+            Self::ReadOutOfMemory(addr) => write!(f, "{REOM} at 0x{addr:X}"),
+            Self::WriteOutOfMemory(addr) => write!(f, "{WEOM} at 0x{addr:X}"),
+            Self::Device { addr, reason, .. } => write!(f, "{reason} at 0x{addr:X}"),
+            Self::DivisionByZero => f.write_str("DIVISION BY ZERO"),
+            Self::PrivilegedAccess(addr) => write!(f, "PRIVILEGE VIOLATION at 0x{addr:X}"),
+            Self::PrivilegedInstruction(opc) => {
+                write!(f, "PRIVILEGE VIOLATION for opcode 0x{opc:02X}")
+            }
+            Self::UnhandledSystemCall(value) => {
+                write!(f, "SYCALL {value} without a trap handler")
+            }
+        }
+    }
+}
+
+impl From<Fault> for String {
+    fn from(fault: Fault) -> Self {
+        fault.to_string()
+    }
+}
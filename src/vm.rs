@@ -1,52 +1,191 @@
-use crate::{instruction::Instruction, loader, opc, registers::Registers, uvm, REG_LEN};
+use crate::{
+    bus::{Bus, Device, Region, REOM, WEOM},
+    coredump::CoreDump,
+    debug_info::DebugInfo,
+    devices,
+    instruction::{Instruction, Print},
+    limits::{Budget, Limits, Resource},
+    loader, opc, reg_index,
+    registers::{Registers, REG_COUNT, SR_INTERRUPTS, SR_LINES, SR_USER},
+    snapshot::Snapshot,
+    trap::{Cause, Fault},
+    uvm, REG_LEN,
+};
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    path::Path,
+    rc::Rc,
+};
 
 pub const RAM_LEN: usize = 1024;
 
-const REOM: &str = "READ OUT OF MEMORY";
-const WEOM: &str = "WRITE OUT OF MEMORY";
+/// Trace lines kept for core dumps
+const TRACE_LEN: usize = 64;
 
-pub fn run(program: &[u8]) {
+/// How a run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The program halted with this exit code
+    Exited(uvm),
+    /// A limit was reached before the next instruction, the VM can be
+    /// refueled and resumed
+    ResourceExhausted { kind: Resource },
+    /// The program ran past its last instruction without a trap handler
+    Stopped,
+}
+
+/// Runs a program, or resumes a snapshot of it, within `limits`. With
+/// `snapshot_at`, the state is saved once that many instructions have executed.
+/// A fault writes a core dump to `core_path`. Unless `quiet`, how the program
+/// ended is reported.
+pub fn run(
+    program: &[u8],
+    debug_info: Option<Rc<DebugInfo>>,
+    start: Option<&Snapshot>,
+    limits: Limits,
+    snapshot_at: Option<(u64, &Path)>,
+    core_path: &Path,
+    quiet: bool,
+) -> io::Result<Outcome> {
+    let mut trace = VecDeque::with_capacity(TRACE_LEN);
     let mut vm = VM::new();
     vm.load(program);
-    while let Some(instruction) = vm.decode() {
-        vm.push_stderr(&format!("{:04X} : ", vm.regs.pc));
+    if let Some(snapshot) = start {
+        vm.restore(snapshot).map_err(io::Error::other)?;
+    }
+    if let Some(debug_info) = debug_info {
+        vm.set_debug_info(debug_info);
+    }
+    vm.set_limits(limits);
+    loop {
+        if let Some((cycles, path)) = snapshot_at {
+            if vm.cycles == cycles {
+                vm.snapshot().save(path)?;
+                eprintln!("Snapshot saved to {}", path.display());
+            }
+        }
+
+        let instruction = vm.decode();
+        if instruction.is_some() && vm.exhausted().is_none() {
+            vm.push_trace(&format!("{:04X} : ", vm.regs.pc));
+        }
 
-        match vm.execute(instruction) {
-            Ok(Some(exit_code)) => {
-                println!("Program exited with code : {exit_code}");
-                break;
+        let result = vm.step();
+
+        let lines = vm.trace();
+        eprint!("{lines}");
+        for line in lines.lines() {
+            if trace.len() == TRACE_LEN {
+                trace.pop_front();
             }
-            Ok(None) => (),
-            Err(err) => panic!("{err}"),
+            trace.push_back(line.to_string());
         }
 
-        eprint!("{}", vm.stderr());
-        print!("{}", vm.stdout());
+        match result {
+            Ok(Some(outcome @ Outcome::Exited(exit_code))) => {
+                if !quiet {
+                    println!("Program exited with code : {exit_code}");
+                }
+                return Ok(outcome);
+            }
+            Ok(Some(outcome @ Outcome::ResourceExhausted { kind })) => {
+                if !quiet {
+                    eprintln!(
+                        "Program stopped : {kind} reached after {} instructions",
+                        vm.cycles
+                    );
+                }
+                return Ok(outcome);
+            }
+            Ok(Some(outcome @ Outcome::Stopped)) => return Ok(outcome),
+            Ok(None) => (),
+            Err(err) => {
+                let saved = CoreDump {
+                    snapshot: vm.snapshot(),
+                    program_len: program.len() as u64,
+                    fault: err.clone(),
+                    instruction: instruction.map_or_else(String::new, |i| format!("{i:?}")),
+                    trace: trace.into(),
+                }
+                .save(core_path);
+                // The fault is reported first, a core dump that cannot be
+                // written only earns a warning after it
+                return Err(io::Error::other(match saved {
+                    Ok(()) => format!("{err}, core dumped to {}", core_path.display()),
+                    Err(save_err) => format!(
+                        "{err}\nWarning: cannot write core dump to {} : {save_err}",
+                        core_path.display()
+                    ),
+                }));
+            }
+        }
     }
 }
 
+/// Shadow stack entry, pushed by CALL and popped by RET
+#[derive(Clone, Copy)]
+pub struct Frame {
+    /// Address of the CALL instruction
+    pub call_site: uvm,
+    /// Entry point of the called function
+    pub function: uvm,
+    /// BP of the caller at the time of the call
+    pub bp: uvm,
+}
+
+/// Output stream of the program
+#[derive(Clone, Copy)]
+pub enum Stream {
+    /// Written by PRINT, the process stdout by default
+    Stdout,
+    /// Written by EPRINT, the process stderr by default
+    Stderr,
+    /// Written by DUMP, the process stderr by default
+    Diagnostics,
+}
+
 pub struct VM {
     regs: Registers,
-    ram: [u8; RAM_LEN],
-    stdout: String,
-    stderr: String,
+    bus: Bus,
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+    diagnostics: Box<dyn Write>,
+    /// Execution trace of the instructions since the last `trace` call
+    trace: String,
+    /// Instructions executed so far
+    cycles: u64,
+    frames: Vec<Frame>,
+    /// Interrupt lines raised and not handled yet, as a mask
+    pending: u64,
+    budget: Budget,
+    debug_info: Option<Rc<DebugInfo>>,
 }
 
 impl VM {
     pub fn new() -> Self {
-        let mut ram = [0u8; RAM_LEN];
-        ram.iter_mut().for_each(|b| *b = rand::random());
+        let mut bus = Bus::new();
+        for (addr, device) in devices::standard(bus.window()) {
+            bus.map(addr, device).expect("Standard devices overlap");
+        }
         Self {
             regs: Registers::default(),
-            ram,
-            stdout: String::new(),
-            stderr: String::new(),
+            bus,
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            diagnostics: Box::new(io::stderr()),
+            trace: String::new(),
+            cycles: 0,
+            frames: Vec::new(),
+            pending: 0,
+            budget: Budget::default(),
+            debug_info: None,
         }
     }
 
     pub fn load(&mut self, program: &[u8]) -> uvm {
         for (idx, byte) in program.iter().enumerate() {
-            *self.ram.get_mut(idx).expect(WEOM) = *byte;
+            *self.bus.ram_mut().get_mut(idx).expect(WEOM) = *byte;
         }
         let end = program.len() as uvm;
         self.regs.sp = end;
@@ -54,56 +193,196 @@ impl VM {
         end
     }
 
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
     pub fn pc(&self) -> uvm {
         self.regs.pc
     }
 
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
     pub fn sp(&self) -> uvm {
         self.regs.sp
     }
 
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn bp(&self) -> uvm {
+        self.regs.bp
+    }
+
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cycles: self.cycles,
+            registers: self.regs.values(),
+            ram: self.bus.ram().to_vec(),
+            frames: self.frames.clone(),
+            pending: self.pending,
+            devices: self.bus.save(),
+        }
+    }
+
+    /// Replaces the whole state by that of a snapshot, keeping the debug info
+    /// and output. Fails without changing anything when the snapshot was taken
+    /// with other devices.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        let ram = snapshot.ram.len();
+        if ram != RAM_LEN {
+            return Err(format!("{ram} bytes of RAM, expected {RAM_LEN}"));
+        }
+        self.bus.restore(&snapshot.devices)?;
+        self.pending = snapshot.pending;
+        self.cycles = snapshot.cycles;
+        self.regs = Registers::from_values(snapshot.registers);
+        self.bus.ram_mut().copy_from_slice(&snapshot.ram);
+        self.frames.clone_from(&snapshot.frames);
+        Ok(())
+    }
+
+    /// Calls in progress, outermost first
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Replaces the remaining budget by `limits`
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.budget = Budget::new(limits);
+    }
+
+    /// Adds to the remaining budget, to resume after running out
+    #[cfg_attr(not(test), allow(unused))]
+    pub fn refuel(&mut self, limits: Limits) {
+        self.budget.refuel(limits);
+    }
+
+    /// Resource used up, the next step will not execute anything
+    pub fn exhausted(&self) -> Option<Resource> {
+        self.budget.exhausted()
+    }
+
+    pub fn set_debug_info(&mut self, debug_info: Rc<DebugInfo>) {
+        self.debug_info = Some(debug_info);
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_deref()
+    }
+
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
     pub fn get_reg(&self, idx: uvm) -> Result<uvm, String> {
-        self.regs.get(idx)
+        Ok(self.regs.get(idx)?)
+    }
+
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn set_reg(&mut self, idx: uvm, value: uvm) -> Result<(), String> {
+        Ok(self.regs.set(idx, value)?)
+    }
+
+    /// Maps a device on the bus, see `Bus::map`
+    #[cfg_attr(not(test), allow(unused))]
+    pub fn map_device(&mut self, start: uvm, device: Box<dyn Device>) -> Result<(), String> {
+        self.bus.map(start, device)
+    }
+
+    /// RAM and devices on the bus
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn device_map(&self) -> Vec<Region> {
+        self.bus.regions()
+    }
+
+    /// Reads RAM, without going through the devices
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn read_ram(&self, addr: uvm, len: usize) -> Result<&[u8], String> {
+        let addr = addr as usize;
+        self.bus
+            .ram()
+            .get(addr..addr.saturating_add(len))
+            .ok_or_else(|| format!("{REOM} at 0x{addr:X}"))
+    }
+
+    /// Writes RAM, without going through the devices
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn write_ram(&mut self, addr: uvm, bytes: &[u8]) -> Result<(), String> {
+        let addr = addr as usize;
+        self.bus
+            .ram_mut()
+            .get_mut(addr..addr.saturating_add(bytes.len()))
+            .ok_or_else(|| format!("{WEOM} at 0x{addr:X}"))?
+            .copy_from_slice(bytes);
+        Ok(())
     }
 
-    fn push_stdout(&mut self, string: &str) {
-        for char in string.chars() {
-            self.stdout.push(char);
+    fn read_word(&mut self, addr: uvm) -> Result<uvm, Fault> {
+        let mut word = [0; REG_LEN];
+        self.bus.read(addr, &mut word)?;
+        Ok(uvm::from_le_bytes(word))
+    }
+
+    /// Bytes from `addr` up to the next NUL
+    fn read_string(&mut self, addr: uvm) -> Result<Vec<u8>, Fault> {
+        let mut bytes = Vec::new();
+        loop {
+            let mut byte = [0];
+            self.bus
+                .read(addr.wrapping_add(bytes.len() as uvm), &mut byte)?;
+            match byte {
+                [0] => return Ok(bytes),
+                [byte] => bytes.push(byte),
+            }
         }
     }
 
-    pub fn stdout(&mut self) -> String {
-        let mut str = String::with_capacity(self.stdout.len());
-        std::mem::swap(&mut self.stdout, &mut str);
-        self.stdout.clear();
-        str
+    /// Sets where an output stream of the program goes
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn set_output(&mut self, stream: Stream, sink: Box<dyn Write>) {
+        *self.sink(stream) = sink;
     }
 
-    fn push_stderr(&mut self, string: &str) {
-        for char in string.chars() {
-            self.stderr.push(char);
+    fn sink(&mut self, stream: Stream) -> &mut Box<dyn Write> {
+        match stream {
+            Stream::Stdout => &mut self.stdout,
+            Stream::Stderr => &mut self.stderr,
+            Stream::Diagnostics => &mut self.diagnostics,
         }
     }
 
-    pub fn stderr(&mut self) -> String {
-        let mut str = String::with_capacity(self.stderr.len());
-        std::mem::swap(&mut self.stderr, &mut str);
-        self.stderr.clear();
-        str
+    /// Writes guest output, dropping what exceeds the output budget
+    fn write_output(&mut self, stream: Stream, bytes: &[u8]) -> Result<(), Fault> {
+        let len = self.budget.spend_output(bytes.len());
+        let sink = self.sink(stream);
+        sink.write_all(bytes.get(..len).unwrap_or_default())
+            .and_then(|()| sink.flush())
+            .map_err(|err| Fault::Output(format!("Cannot write output : {err}")))
+    }
+
+    fn push_trace(&mut self, string: &str) {
+        self.trace.push_str(string);
     }
 
-    pub fn show_regs(&self) -> Result<Vec<String>, String> {
-        self.regs.show()
+    /// Takes the trace of the instructions executed since the last call
+    pub fn trace(&mut self) -> String {
+        std::mem::take(&mut self.trace)
     }
 
-    pub fn show_ram(&self) -> Vec<String> {
-        self.ram.iter().map(|byte| format!("{byte:02X}")).collect()
+    /// Values of all registers, in index order
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn registers(&self) -> [uvm; REG_COUNT as usize] {
+        self.regs.values()
     }
 
+    #[cfg_attr(not(feature = "debugger"), allow(unused))]
+    pub fn ram(&self) -> &[u8] {
+        self.bus.ram()
+    }
+
+    #[cfg_attr(not(feature = "tui"), allow(unused))]
     pub fn show_program(&self) -> Vec<(Instruction, usize)> {
         let mut program = Vec::new();
         let mut addr = 0;
-        while let Some(instruction) = loader::decode(&self.ram, addr) {
+        while let Some(instruction) = loader::decode(self.bus.ram(), addr) {
             program.push((instruction, addr));
             addr += instruction.len();
         }
@@ -111,29 +390,206 @@ impl VM {
     }
 
     pub fn decode(&self) -> Option<Instruction> {
-        loader::decode(&self.ram, self.regs.pc as usize)
+        loader::decode(self.bus.ram(), self.regs.pc as usize)
+    }
+
+    /// Executes the next instruction, unless a limit is reached first
+    pub fn step(&mut self) -> Result<Option<Outcome>, String> {
+        if let Some(kind) = self.exhausted() {
+            return Ok(Some(Outcome::ResourceExhausted { kind }));
+        }
+        let Some(instruction) = self.decode() else {
+            return Ok(match self.trap(Fault::NoInstruction(self.regs.pc)) {
+                Ok(()) => None,
+                Err(_) => Some(Outcome::Stopped),
+            });
+        };
+        Ok(self.execute(instruction)?.map(Outcome::Exited))
+    }
+
+    /// Runs until the program exits or a limit is reached, leaving its output
+    /// in the VM and discarding the trace
+    #[cfg_attr(not(test), allow(unused))]
+    pub fn resume(&mut self) -> Result<Outcome, String> {
+        loop {
+            let outcome = self.step();
+            self.trace.clear();
+            if let Some(outcome) = outcome? {
+                return Ok(outcome);
+            }
+        }
     }
 
     pub fn execute(&mut self, instruction: Instruction) -> Result<Option<uvm>, String> {
+        let pc = self.regs.pc;
+        let result = self
+            .execute_at(instruction)
+            .or_else(|fault| {
+                self.push_trace("\n");
+                self.trap(fault).map(|()| None)
+            })
+            .and_then(|exit_code| {
+                self.cycles += 1;
+                self.pending |= self.bus.tick();
+                self.budget.spend_fuel();
+                if exit_code.is_none() {
+                    self.interrupt()?;
+                }
+                Ok(exit_code)
+            });
+        result.map_err(
+            |fault| match self.debug_info().and_then(|info| info.symbolize(pc)) {
+                Some(location) => format!("{fault} {location}"),
+                None => fault.to_string(),
+            },
+        )
+    }
+
+    /// Enters the handler of the first pending interrupt, if it is enabled.
+    /// The interrupted PC and SR are pushed, and interrupts are disabled.
+    fn interrupt(&mut self) -> Result<(), Fault> {
+        self.bus.set_user(false);
+        let sr = self.regs.sr;
+        if sr & SR_INTERRUPTS == 0 {
+            return Ok(());
+        }
+        let lines = (1 << devices::INTERRUPT_LINES) - 1;
+        let ready = self.pending & (sr >> SR_LINES) & lines;
+        if ready == 0 {
+            return Ok(());
+        }
+        let line = ready.trailing_zeros();
+        self.pending &= !(1 << line);
+        let handler = self.read_word(devices::VECTORS + uvm::from(line) * REG_LEN as uvm)?;
+        if handler == 0 {
+            return Ok(());
+        }
+        self.enter(&format!("INTERRUPT {line}"), handler)
+    }
+
+    /// Enters the trap handler with the cause of `fault`, or returns `fault`
+    /// when it cannot be trapped, there is no handler or it cannot be entered
+    fn trap(&mut self, fault: Fault) -> Result<(), Fault> {
+        match fault.cause() {
+            Some(cause) if self.raise(cause, 0) == Ok(true) => Ok(()),
+            _ => Err(fault),
+        }
+    }
+
+    /// Enters the trap handler, recording `cause` and `value` for it. Returns
+    /// whether there is a handler.
+    fn raise(&mut self, cause: Cause, value: uvm) -> Result<bool, Fault> {
+        self.bus.set_user(false);
+        let handler = self.read_word(devices::TRAP_HANDLER)?;
+        if handler == 0 {
+            return Ok(false);
+        }
+        self.bus
+            .write(devices::TRAP_CAUSE, &cause.code().to_le_bytes())?;
+        self.bus.write(devices::TRAP_VALUE, &value.to_le_bytes())?;
+        self.enter(&format!("TRAP {}", cause.code()), handler)?;
+        Ok(true)
+    }
+
+    /// Pushes PC then SR on the supervisor stack, then jumps to `handler` in
+    /// supervisor mode with interrupts disabled. Coming from user mode, SP is
+    /// exchanged with the banked stack pointer first.
+    fn enter(&mut self, event: &str, handler: uvm) -> Result<(), Fault> {
+        let (pc, sr) = (self.regs.pc, self.regs.sr);
+        let user = sr & SR_USER != 0;
+        self.bus.set_user(false);
+        let sp = if user {
+            self.read_word(devices::STACK)?
+        } else {
+            self.regs.sp
+        };
+        self.bus.write(sp, &pc.to_le_bytes())?;
+        self.bus
+            .write(sp.wrapping_add(REG_LEN as uvm), &sr.to_le_bytes())?;
+        if user {
+            self.bus
+                .write(devices::STACK, &self.regs.sp.to_le_bytes())?;
+        }
+        self.regs.sp = sp.wrapping_add(2 * REG_LEN as uvm);
+        self.regs.sr = sr & !SR_INTERRUPTS & !SR_USER;
+        self.regs.pc = handler;
+        self.frames.push(Frame {
+            call_site: pc,
+            function: handler,
+            bp: self.regs.bp,
+        });
+
+        if !self.trace.is_empty() && !self.trace.ends_with('\n') {
+            self.push_trace("\n");
+        }
+        self.push_trace(&format!(
+            "{pc:04X} : {event} => @0x{sp:X} = {pc}, SR, JMP 0x{handler:X}\n"
+        ));
+        Ok(())
+    }
+
+    /// Rejects what user mode may not do: running code outside of its window,
+    /// HALT, IRET, and writing SR
+    fn check_user(&self, instruction: Instruction) -> Result<(), Fault> {
+        let pc = self.regs.pc;
+        if !self.bus.in_window(pc, instruction.len()) {
+            return Err(Fault::PrivilegedAccess(pc));
+        }
+        if matches!(instruction.opc, opc!(HALT) | opc!(IRET))
+            || instruction.target_regs().0.contains(&reg_index!(sr))
+        {
+            return Err(Fault::PrivilegedInstruction(instruction.opc));
+        }
+        Ok(())
+    }
+
+    fn execute_at(&mut self, instruction: Instruction) -> Result<Option<uvm>, Fault> {
         let Instruction { rfl, opc, reg, val } = instruction;
         let pc = self.regs.pc;
         let reg = reg.into();
 
-        self.push_stderr(&format!("{instruction:?}"));
+        self.push_trace(&format!("{instruction:?}"));
+
+        let user = self.regs.sr & SR_USER != 0;
+        self.bus.set_user(user);
+        if user {
+            self.check_user(instruction)?;
+        }
+
+        if opc == opc!(SYCALL) {
+            let value = if rfl { self.regs.get(val)? } else { val };
+            self.regs.pc = pc.wrapping_add(instruction.len() as uvm);
+            return match self.raise(Cause::SystemCall, value) {
+                Ok(true) => Ok(None),
+                Ok(false) => {
+                    self.regs.pc = pc;
+                    Err(Fault::UnhandledSystemCall(value))
+                }
+                Err(fault) => {
+                    self.regs.pc = pc;
+                    Err(fault)
+                }
+            };
+        }
 
         if opc == opc!(HALT) {
-            self.push_stderr("\n");
+            self.push_trace("\n");
             return Ok(Some(halt(self, rfl, val)?));
         }
 
         match opc {
             opc!(NOP) => nop(),
+            opc!(CLEAR) => set(self, false, reg, 0)?,
             opc!(SET) => set(self, rfl, reg, val)?,
             opc!(LOAD) => load(self, rfl, reg, val)?,
             opc!(STOREB) => store(self, rfl, reg, val, 1)?,
             opc!(STOREH) => store(self, rfl, reg, val, 2)?,
             opc!(STOREW) => store(self, rfl, reg, val, 4)?,
             opc!(STORED) => store(self, rfl, reg, val, 8)?,
+            opc!(SWAP) => swap(self, rfl, reg, val)?,
+            opc!(NEG) => unop(self, reg, uvm::wrapping_neg)?,
+            opc!(INC) => unop(self, reg, |a| a.wrapping_add(1))?,
+            opc!(DEC) => unop(self, reg, |a| a.wrapping_sub(1))?,
             opc!(ADD) => add(self, rfl, reg, val)?,
             opc!(SUB) => sub(self, rfl, reg, val)?,
             opc!(MUL) => mul(self, rfl, reg, val)?,
@@ -141,65 +597,87 @@ impl VM {
             opc!(MOD) => modl(self, rfl, reg, val)?,
             opc!(AND) => and(self, rfl, reg, val)?,
             opc!(PUSH) => push(self, rfl, val)?,
+            opc!(DUP) => dup(self, rfl, val)?,
             opc!(POP) => pop(self, reg)?,
-            opc!(DROP) => drop(self),
+            opc!(DROP) => drop(self)?,
             opc!(CALL) => call(self, rfl, val)?,
             opc!(RET) => ret(self, rfl, val)?,
+            opc!(IRET) => iret(self)?,
             opc!(JMP) => jmp(self, rfl, val)?,
             opc!(JEQ) => jeq(self, rfl, reg, val)?,
             opc!(JNE) => jne(self, rfl, reg, val)?,
-            opc!(PRINT) => stdout(self, rfl, val)?,
-            _ => return Err(format!("Unexpected opcode 0x{opc:02X}")),
+            opc!(PRINT) => print(self, Stream::Stdout, rfl, instruction.reg, val)?,
+            opc!(EPRINT) => print(self, Stream::Stderr, rfl, instruction.reg, val)?,
+            opc!(DUMP) => dump(self, rfl, instruction.reg, val)?,
+            _ => return Err(Fault::UnexpectedOpcode(opc)),
         }
 
         if self.regs.pc == pc {
-            self.regs.pc = pc + instruction.len() as uvm;
+            self.regs.pc = pc.wrapping_add(instruction.len() as uvm);
         }
 
-        self.push_stderr("\n");
+        self.push_trace("\n");
         Ok(None)
     }
 }
 
 fn nop() {}
 
-fn halt(vm: &mut VM, rfl: bool, val: uvm) -> Result<uvm, String> {
+fn halt(vm: &mut VM, rfl: bool, val: uvm) -> Result<uvm, Fault> {
     Ok(if rfl { vm.regs.get(val)? } else { val })
 }
 
-fn set(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), String> {
+fn set(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
     let value = if rfl { vm.regs.get(val)? } else { val };
     vm.regs.set(reg, value)?;
 
-    vm.push_stderr(&format!(" => R_ = {value}"));
+    vm.push_trace(&format!(" => R_ = {value}"));
     Ok(())
 }
 
-fn load(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), String> {
-    let addr = if rfl { vm.regs.get(val)? } else { val } as usize;
-    let bytes = vm
-        .ram
-        .get(addr..addr + REG_LEN)
-        .expect(REOM)
-        .try_into()
-        .expect(REOM);
-    let value = uvm::from_le_bytes(bytes);
+fn load(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
+    let addr = if rfl { vm.regs.get(val)? } else { val };
+    let value = vm.read_word(addr)?;
     vm.regs.set(reg, value)?;
 
-    vm.push_stderr(&format!(" => @0x{addr:X} -> {value}"));
+    vm.push_trace(&format!(" => @0x{addr:X} -> {value}"));
     Ok(())
 }
 
-fn store(vm: &mut VM, rfl: bool, reg: uvm, val: uvm, n_bytes: usize) -> Result<(), String> {
-    let addr = vm.regs.get(reg)? as usize;
+fn store(vm: &mut VM, rfl: bool, reg: uvm, val: uvm, n_bytes: usize) -> Result<(), Fault> {
+    let addr = vm.regs.get(reg)?;
     let value = if rfl { vm.regs.get(val)? } else { val };
     let bytes = uvm::to_le_bytes(value);
-    vm.ram
-        .get_mut(addr..addr + n_bytes)
-        .expect(WEOM)
-        .copy_from_slice(bytes.get(..n_bytes).expect("64 bit store on 32 bit system not implemented")); // TODO
+    vm.bus.write(
+        addr,
+        bytes
+            .get(..n_bytes)
+            .expect("64 bit store on 32 bit system not implemented"),
+    )?; // TODO
+
+    vm.push_trace(&format!(" => @0x{addr:X} = {value}"));
+    Ok(())
+}
+
+fn swap(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
+    if !rfl {
+        return Err(Fault::InvalidOperand(
+            "SWAP needs two registers".to_string(),
+        ));
+    }
+    let (a, b) = (vm.regs.get(reg)?, vm.regs.get(val)?);
+    vm.regs.set(reg, b)?;
+    vm.regs.set(val, a)?;
+
+    vm.push_trace(&format!(" => R_ = {b}, R_ = {a}"));
+    Ok(())
+}
+
+fn unop(vm: &mut VM, reg: uvm, op: fn(uvm) -> uvm) -> Result<(), Fault> {
+    let value = op(vm.regs.get(reg)?);
+    vm.regs.set(reg, value)?;
 
-    vm.push_stderr(&format!(" => @0x{addr:X} = {value}"));
+    vm.push_trace(&format!(" => R_ = {value}"));
     Ok(())
 }
 
@@ -208,96 +686,123 @@ fn binop(
     rfl: bool,
     reg: uvm,
     val: uvm,
-    op: fn(uvm, uvm) -> uvm,
-) -> Result<(), String> {
+    op: fn(uvm, uvm) -> Option<uvm>,
+) -> Result<(), Fault> {
     let val = if rfl { vm.regs.get(val)? } else { val };
-    let value = op(vm.regs.get(reg)?, val);
+    let value = op(vm.regs.get(reg)?, val).ok_or(Fault::DivisionByZero)?;
     vm.regs.set(reg, value)?;
 
-    vm.push_stderr(&format!(" => R_ = {value}"));
+    vm.push_trace(&format!(" => R_ = {value}"));
     Ok(())
 }
 
-fn add(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), String> {
-    binop(vm, rfl, reg, val, |a, b| a + b)
+fn add(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
+    binop(vm, rfl, reg, val, |a, b| Some(a.wrapping_add(b)))
 }
 
-fn sub(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), String> {
-    binop(vm, rfl, reg, val, |a, b| a - b)
+fn sub(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
+    binop(vm, rfl, reg, val, |a, b| Some(a.wrapping_sub(b)))
 }
 
-fn mul(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), String> {
-    binop(vm, rfl, reg, val, |a, b| a * b)
+fn mul(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
+    binop(vm, rfl, reg, val, |a, b| Some(a.wrapping_mul(b)))
 }
 
-fn div(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), String> {
-    binop(vm, rfl, reg, val, |a, b| a / b)
+fn div(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
+    binop(vm, rfl, reg, val, uvm::checked_div)
 }
 
-fn modl(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), String> {
-    binop(vm, rfl, reg, val, |a, b| a % b)
+fn modl(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
+    binop(vm, rfl, reg, val, uvm::checked_rem)
 }
 
-fn and(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), String> {
-    binop(vm, rfl, reg, val, |a, b| a & b)
+fn and(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
+    binop(vm, rfl, reg, val, |a, b| Some(a & b))
 }
 
-fn push(vm: &mut VM, rfl: bool, val: uvm) -> Result<(), String> {
+fn push(vm: &mut VM, rfl: bool, val: uvm) -> Result<(), Fault> {
     let value = if rfl { vm.regs.get(val)? } else { val };
     let bytes = uvm::to_le_bytes(value);
     let sp = vm.regs.sp;
-    vm.ram
-        .get_mut(sp as usize..sp as usize + REG_LEN)
-        .expect(WEOM)
-        .copy_from_slice(&bytes[0..REG_LEN]);
-    vm.regs.sp = sp + REG_LEN as uvm;
+    vm.bus.write(sp, &bytes)?;
+    vm.regs.sp = sp.wrapping_add(REG_LEN as uvm);
 
-    vm.push_stderr(&format!(" => @0x{sp:X} = {value}"));
+    vm.push_trace(&format!(" => @0x{sp:X} = {value}"));
     Ok(())
 }
 
-fn pop(vm: &mut VM, reg: uvm) -> Result<(), String> {
-    let sp = vm.regs.sp - REG_LEN as uvm;
+/// Pushes a copy of the word at an address
+fn dup(vm: &mut VM, rfl: bool, val: uvm) -> Result<(), Fault> {
+    let addr = if rfl { vm.regs.get(val)? } else { val };
+    let value = vm.read_word(addr)?;
+    vm.push_trace(&format!(" => @0x{addr:X} -> {value}"));
+    push(vm, false, value)
+}
+
+fn pop(vm: &mut VM, reg: uvm) -> Result<(), Fault> {
+    let sp = vm.regs.sp.wrapping_sub(REG_LEN as uvm);
+    let value = vm.read_word(sp)?;
     vm.regs.sp = sp;
-    let range = (sp as usize)..(sp as usize) + REG_LEN;
-    let bytes = vm.ram.get(range).expect(REOM).try_into().expect(REOM);
-    let value = uvm::from_le_bytes(bytes);
     vm.regs.set(reg, value)?;
 
-    vm.push_stderr(&format!(" => @0x{sp:X} -> {value}"));
+    vm.push_trace(&format!(" => @0x{sp:X} -> {value}"));
     Ok(())
 }
 
-fn drop(vm: &mut VM) {
-    let sp = vm.regs.sp - REG_LEN as uvm;
+fn drop(vm: &mut VM) -> Result<(), Fault> {
+    let sp = vm.regs.sp.wrapping_sub(REG_LEN as uvm);
+    let value = vm.read_word(sp)?;
     vm.regs.sp = sp;
-    let bytes = vm
-        .ram
-        .get((sp as usize)..(sp as usize) + REG_LEN)
-        .expect(REOM)
-        .try_into()
-        .expect(REOM);
-    let value = uvm::from_le_bytes(bytes);
 
-    vm.push_stderr(&format!(" => @0x{sp:X} -> {value}"));
+    vm.push_trace(&format!(" => @0x{sp:X} -> {value}"));
+    Ok(())
 }
 
-fn call(vm: &mut VM, rfl: bool, val: uvm) -> Result<(), String> {
-    vm.regs.lr = vm.regs.pc + if rfl { 3 } else { 2 + REG_LEN as uvm };
+fn call(vm: &mut VM, rfl: bool, val: uvm) -> Result<(), Fault> {
+    let call_site = vm.regs.pc;
+    vm.regs.lr = call_site.wrapping_add(if rfl { 3 } else { 2 + REG_LEN as uvm });
     jmp(vm, rfl, val)?;
+    vm.frames.push(Frame {
+        call_site,
+        function: vm.regs.pc,
+        bp: vm.regs.bp,
+    });
     Ok(())
 }
 
-fn ret(vm: &mut VM, rfl: bool, val: uvm) -> Result<(), String> {
+fn ret(vm: &mut VM, rfl: bool, val: uvm) -> Result<(), Fault> {
     let value = if rfl { vm.regs.get(val)? } else { val };
     vm.regs.rr = value;
     vm.regs.pc = vm.regs.lr;
+    vm.frames.pop();
 
-    vm.push_stderr(&format!(" => RR = {value}, JMP {}", vm.regs.lr));
+    vm.push_trace(&format!(" => RR = {value}, JMP {}", vm.regs.lr));
     Ok(())
 }
 
-fn jmp(vm: &mut VM, rfl: bool, val: uvm) -> Result<(), String> {
+/// Returns from an interrupt or trap handler, restoring PC and SR. Returning
+/// to user mode exchanges SP with the banked stack pointer.
+fn iret(vm: &mut VM) -> Result<(), Fault> {
+    let sp = vm.regs.sp.wrapping_sub(2 * REG_LEN as uvm);
+    let pc = vm.read_word(sp)?;
+    let sr = vm.read_word(sp.wrapping_add(REG_LEN as uvm))?;
+    let sp = if sr & SR_USER == 0 {
+        sp
+    } else {
+        let banked = vm.read_word(devices::STACK)?;
+        vm.bus.write(devices::STACK, &sp.to_le_bytes())?;
+        banked
+    };
+    vm.regs.sp = sp;
+    vm.regs.sr = sr;
+    vm.regs.pc = pc;
+    vm.frames.pop();
+
+    vm.push_trace(&format!(" => SR = {sr}, SP = {sp}, JMP {pc}"));
+    Ok(())
+}
+
+fn jmp(vm: &mut VM, rfl: bool, val: uvm) -> Result<(), Fault> {
     let addr = if rfl { vm.regs.get(val)? } else { val };
     vm.regs.pc = addr;
     Ok(())
@@ -309,31 +814,609 @@ fn jcond(
     reg: uvm,
     val: uvm,
     op: fn(&uvm, &uvm) -> bool,
-) -> Result<(), String> {
+) -> Result<(), Fault> {
     let cond = op(&vm.regs.get(reg)?, &0);
     if cond {
         let addr = if rfl { vm.regs.get(val)? } else { val };
         vm.regs.pc = addr;
     }
 
-    vm.push_stderr(&format!(" => {cond}"));
+    vm.push_trace(&format!(" => {cond}"));
     Ok(())
 }
 
-fn jeq(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), String> {
+fn jeq(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
     jcond(vm, rfl, reg, val, uvm::eq)
 }
 
-fn jne(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), String> {
+fn jne(vm: &mut VM, rfl: bool, reg: uvm, val: uvm) -> Result<(), Fault> {
     jcond(vm, rfl, reg, val, uvm::ne)
 }
 
-fn stdout(vm: &mut VM, rfl: bool, val: uvm) -> Result<(), String> {
+fn print(vm: &mut VM, stream: Stream, rfl: bool, mode: u8, val: uvm) -> Result<(), Fault> {
     let value = if rfl { vm.regs.get(val)? } else { val };
-    let chars = value.to_le_bytes();
-    let str = String::from_utf8(chars.to_vec()).expect("Invalid string !");
-    vm.push_stdout(&str);
+    let word = value.to_le_bytes();
+    let bytes = match Print::from_reg(mode) {
+        Some(Print::Word) => word
+            .split(|byte| *byte == 0)
+            .next()
+            .unwrap_or_default()
+            .to_vec(),
+        Some(Print::Bytes(len)) => word.get(..len).unwrap_or_default().to_vec(),
+        Some(Print::String) => vm.read_string(value)?,
+        Some(Print::Unsigned) => value.to_string().into_bytes(),
+        Some(Print::Signed) => value.cast_signed().to_string().into_bytes(),
+        Some(Print::Hex) => format!("{value:X}").into_bytes(),
+        None => {
+            return Err(Fault::InvalidOperand(format!(
+                "Invalid PRINT mode 0x{mode:02X}"
+            )))
+        }
+    };
+    vm.write_output(stream, &bytes)?;
+
+    vm.push_trace(&format!(" => \"{}\"", bytes.escape_ascii()));
+    Ok(())
+}
+
+/// Writes the registers, then `len` bytes of memory from the address
+fn dump(vm: &mut VM, rfl: bool, len: u8, val: uvm) -> Result<(), Fault> {
+    let addr = if rfl { vm.regs.get(val)? } else { val };
+    let mut lines = vec![format!("DUMP at 0x{:04X}", vm.regs.pc)];
+    let registers = vm.regs.values();
+    lines.extend(registers.chunks(4).enumerate().map(|(row, values)| {
+        let columns = values.iter().enumerate().map(|(col, value)| {
+            let name = Registers::register_name((row * 4 + col) as uvm);
+            format!("{name} {value:016X}")
+        });
+        columns.collect::<Vec<_>>().join("  ")
+    }));
+    let mut memory = vec![0; len.into()];
+    vm.bus.read(addr, &mut memory)?;
+    lines.extend(memory.chunks(16).enumerate().map(|(row, bytes)| {
+        let hex = bytes.iter().map(|byte| format!("{byte:02X}"));
+        let ascii = bytes
+            .iter()
+            .map(|byte| match byte {
+                0x20..=0x7E => char::from(*byte),
+                _ => '.',
+            })
+            .collect::<String>();
+        let row_addr = addr.wrapping_add(row as uvm * 16);
+        let hex = hex.collect::<Vec<_>>().join(" ");
+        format!("{row_addr:08X}  {hex:<47}  {ascii}")
+    }));
+    let text = lines.join("\n") + "\n";
+    vm.write_output(Stream::Diagnostics, text.as_bytes())?;
 
-    vm.push_stderr(&format!(" => {str:?}"));
+    vm.push_trace(&format!(" => {len} bytes at 0x{addr:X}"));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reg_index;
+    use std::cell::{Cell, RefCell};
+
+    const R0: u8 = reg_index!(r0);
+    const R1: u8 = reg_index!(r1);
+
+    /// Encodes an instruction, `val` being a register index when `rfl`
+    fn encode(opc: u8, reg: u8, val: uvm, rfl: bool) -> Vec<u8> {
+        let mut bytes = vec![opc | u8::from(rfl) << 7, reg];
+        if rfl {
+            bytes.push(val as u8);
+        } else {
+            bytes.extend(val.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Loads a single instruction with R0 = 5 and R1 = 7, then executes it
+    fn execute(opc: u8, reg: u8, val: uvm, rfl: bool) -> (VM, Result<Option<uvm>, String>) {
+        let program = encode(opc, reg, val, rfl);
+        let mut vm = VM::new();
+        vm.load(&program);
+        vm.set_reg(R0.into(), 5).unwrap();
+        vm.set_reg(R1.into(), 7).unwrap();
+        let instruction = vm.decode().unwrap();
+        let result = vm.execute(instruction);
+        if result.is_ok() {
+            assert_eq!(vm.pc(), program.len() as uvm);
+        }
+        (vm, result)
+    }
+
+    fn reg(vm: &VM, idx: u8) -> uvm {
+        vm.get_reg(idx.into()).unwrap()
+    }
+
+    /// Loads a program made of `instructions`
+    fn boot(instructions: &[Vec<u8>]) -> VM {
+        let mut vm = VM::new();
+        vm.load(&instructions.concat());
+        vm
+    }
+
+    /// Executes the instruction at `PC`
+    fn step(vm: &mut VM) -> Result<Option<uvm>, String> {
+        let instruction = vm.decode().unwrap();
+        vm.execute(instruction)
+    }
+
+    /// Writes a word through the bus, in supervisor mode
+    fn poke(vm: &mut VM, addr: uvm, value: uvm) {
+        vm.bus.write(addr, &value.to_le_bytes()).unwrap();
+    }
+
+    /// Output sink shared with the test
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Sends `stream` to a buffer, returned to read what the program wrote
+    fn capture(vm: &mut VM, stream: Stream) -> Rc<RefCell<Vec<u8>>> {
+        let buffer = Rc::default();
+        vm.set_output(stream, Box::new(Buffer(Rc::clone(&buffer))));
+        buffer
+    }
+
+    /// Device keeping the last word written to it, shared with the test
+    struct Latch(Rc<Cell<uvm>>);
+
+    impl Device for Latch {
+        fn name(&self) -> &'static str {
+            "latch"
+        }
+
+        fn len(&self) -> uvm {
+            REG_LEN as uvm
+        }
+
+        fn read(&mut self, _offset: uvm, buf: &mut [u8]) -> Result<(), String> {
+            let word = buf.len() == REG_LEN;
+            word.then(|| buf.copy_from_slice(&self.0.get().to_le_bytes()))
+                .ok_or_else(|| "Word accesses only".to_string())
+        }
+
+        fn write(&mut self, _offset: uvm, bytes: &[u8]) -> Result<(), String> {
+            let word = bytes.try_into().map_err(|_| "Word accesses only")?;
+            self.0.set(uvm::from_le_bytes(word));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn clear() {
+        for rfl in [false, true] {
+            let (vm, result) = execute(opc!(CLEAR), R0, R1.into(), rfl);
+            assert_eq!(result, Ok(None));
+            assert_eq!(reg(&vm, R0), 0);
+            assert_eq!(reg(&vm, R1), 7);
+        }
+    }
+
+    #[test]
+    fn swap() {
+        let (vm, result) = execute(opc!(SWAP), R0, R1.into(), true);
+        assert_eq!(result, Ok(None));
+        assert_eq!((reg(&vm, R0), reg(&vm, R1)), (7, 5));
+
+        let (vm, result) = execute(opc!(SWAP), R0, R1.into(), false);
+        assert!(result.is_err());
+        assert_eq!((reg(&vm, R0), reg(&vm, R1)), (5, 7));
+    }
+
+    #[test]
+    fn neg() {
+        for rfl in [false, true] {
+            let (vm, result) = execute(opc!(NEG), R0, R1.into(), rfl);
+            assert_eq!(result, Ok(None));
+            assert_eq!(reg(&vm, R0), (-5i64).cast_unsigned());
+            assert_eq!(reg(&vm, R1), 7);
+        }
+    }
+
+    #[test]
+    fn inc() {
+        for rfl in [false, true] {
+            let (vm, result) = execute(opc!(INC), R0, R1.into(), rfl);
+            assert_eq!(result, Ok(None));
+            assert_eq!(reg(&vm, R0), 6);
+            assert_eq!(reg(&vm, R1), 7);
+        }
+    }
+
+    #[test]
+    fn dec() {
+        for rfl in [false, true] {
+            let (vm, result) = execute(opc!(DEC), R0, R1.into(), rfl);
+            assert_eq!(result, Ok(None));
+            assert_eq!(reg(&vm, R0), 4);
+            assert_eq!(reg(&vm, R1), 7);
+        }
+    }
+
+    #[test]
+    fn dup() {
+        for (rfl, val) in [(false, 0x100), (true, R1.into())] {
+            let program = encode(opc!(DUP), 0, val, rfl);
+            let mut vm = VM::new();
+            let sp = vm.load(&program);
+            vm.set_reg(R1.into(), 0x100).unwrap();
+            vm.write_ram(0x100, &42u64.to_le_bytes()).unwrap();
+            let instruction = vm.decode().unwrap();
+            assert_eq!(vm.execute(instruction), Ok(None));
+            assert_eq!(vm.sp(), sp + REG_LEN as uvm);
+            assert_eq!(vm.read_word(sp), Ok(42));
+            assert_eq!(vm.pc(), program.len() as uvm);
+        }
+    }
+
+    /// Executes `PRINT mode value`, with `hello` at 0x200, and returns what
+    /// it wrote to stdout
+    fn print(mode: u8, value: uvm) -> Result<Vec<u8>, String> {
+        let mut vm = boot(&[encode(opc!(PRINT), mode, value, false)]);
+        vm.write_ram(0x200, b"hello\0").unwrap();
+        let stdout = capture(&mut vm, Stream::Stdout);
+        step(&mut vm)?;
+        Ok(stdout.take())
+    }
+
+    #[test]
+    fn print_modes() {
+        let word = uvm::from_le_bytes(*b"hi\0there");
+        let minus_two = (-2i64).cast_unsigned();
+        for (mode, value, output) in [
+            (0x00, word, &b"hi"[..]),
+            (0x03, word, b"hi\0"),
+            (0x08, word, b"hi\0there"),
+            (0x10, 0x200, b"hello"),
+            (0x11, minus_two, b"18446744073709551614"),
+            (0x12, minus_two, b"-2"),
+            (0x13, 0xBEEF, b"BEEF"),
+        ] {
+            assert_eq!(print(mode, value).as_deref(), Ok(output), "mode {mode}");
+        }
+    }
+
+    #[test]
+    fn print_undefined_mode() {
+        for mode in [0x09, 0x0F, 0x14, 0xFF] {
+            let err = print(mode, 0).unwrap_err();
+            assert!(err.starts_with(&format!("Invalid PRINT mode 0x{mode:02X}")));
+        }
+    }
+
+    #[test]
+    fn eprint() {
+        let mut vm = boot(&[encode(opc!(EPRINT), 0x11, 42, false)]);
+        let stdout = capture(&mut vm, Stream::Stdout);
+        let stderr = capture(&mut vm, Stream::Stderr);
+        assert_eq!(step(&mut vm), Ok(None));
+        assert_eq!(stderr.take(), b"42");
+        assert!(stdout.take().is_empty());
+    }
+
+    #[test]
+    fn dump() {
+        let mut vm = boot(&[encode(opc!(DUMP), 4, 0x200, false)]);
+        vm.write_ram(0x200, b"AB\0\x7F").unwrap();
+        vm.set_reg(R0.into(), 5).unwrap();
+        let stderr = capture(&mut vm, Stream::Stderr);
+        let diagnostics = capture(&mut vm, Stream::Diagnostics);
+        assert_eq!(step(&mut vm), Ok(None));
+        assert!(stderr.take().is_empty());
+
+        let text = String::from_utf8(diagnostics.take()).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines.first(), Some(&"DUMP at 0x0000"));
+        assert!(lines.get(2).unwrap().ends_with("R0 0000000000000005"));
+        assert!(lines.get(4).unwrap().starts_with("R5 "));
+        let memory = format!("00000200  {:<47}  AB..", "41 42 00 7F");
+        assert_eq!(lines.last(), Some(&memory.as_str()));
+    }
+
+    #[test]
+    fn refuel() {
+        let nop = encode(opc!(NOP), 0, 0, true);
+        let mut vm = boot(&[
+            nop.clone(),
+            nop.clone(),
+            nop,
+            encode(opc!(HALT), 0, 7, false),
+        ]);
+        vm.set_limits(Limits {
+            fuel: Some(2),
+            ..Limits::default()
+        });
+        assert_eq!(
+            vm.resume(),
+            Ok(Outcome::ResourceExhausted {
+                kind: Resource::Fuel
+            })
+        );
+        assert_eq!(vm.cycles(), 2);
+
+        vm.refuel(Limits {
+            fuel: Some(10),
+            ..Limits::default()
+        });
+        assert_eq!(vm.resume(), Ok(Outcome::Exited(7)));
+        assert_eq!(vm.cycles(), 4);
+    }
+
+    #[test]
+    fn device_map() {
+        let vm = VM::new();
+        let regions: Vec<_> = vm
+            .device_map()
+            .into_iter()
+            .map(|region| (region.start, region.name))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                (0, "RAM".to_string()),
+                (devices::CLOCK, "clock".to_string()),
+                (devices::RANDOM, "random".to_string()),
+                (devices::TIMER, "timer".to_string()),
+                (devices::MMU, "mmu".to_string()),
+                (devices::VECTORS, "vectors".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn map_overlap() {
+        let mut vm = VM::new();
+        let latch = || Box::new(Latch(Rc::default()));
+        assert!(vm.map_device(RAM_LEN as uvm - 4, latch()).is_err());
+        assert!(vm.map_device(devices::CLOCK + 4, latch()).is_err());
+        assert!(vm.map_device(uvm::MAX - 4, latch()).is_err());
+        assert!(vm.map_device(0x2_0000, latch()).is_ok());
+        assert!(vm.map_device(0x2_0000, latch()).is_err());
+    }
+
+    #[test]
+    fn custom_device() {
+        let value = Rc::new(Cell::new(0));
+        let mut vm = boot(&[
+            encode(opc!(SET), R0, 0x2_0000, false),
+            encode(opc!(STORED), R0, 42, false),
+            encode(opc!(LOAD), R1, 0x2_0000, false),
+        ]);
+        vm.map_device(0x2_0000, Box::new(Latch(value.clone())))
+            .unwrap();
+
+        for _ in 0..2 {
+            vm.execute(vm.decode().unwrap()).unwrap();
+        }
+        assert_eq!(value.get(), 42);
+
+        value.set(7);
+        vm.execute(vm.decode().unwrap()).unwrap();
+        assert_eq!(reg(&vm, R1), 7);
+    }
+
+    #[test]
+    fn snapshot_devices() {
+        let mut vm = VM::new();
+        poke(&mut vm, devices::TIMER, 3);
+        poke(&mut vm, devices::VECTORS, 0x80);
+        vm.pending = 1;
+        let snapshot = Snapshot::decode(&vm.snapshot().encode()).unwrap();
+
+        let mut restored = VM::new();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.read_word(devices::TIMER), Ok(3));
+        assert_eq!(restored.read_word(devices::VECTORS), Ok(0x80));
+        assert_eq!(restored.pending, 1);
+    }
+
+    #[test]
+    fn restore_all_or_nothing() {
+        let mut vm = VM::new();
+        vm.load(&[0x80, 0, 0]);
+        poke(&mut vm, devices::TIMER, 3);
+        vm.pending = 1;
+        let mut snapshot = vm.snapshot();
+        if let Some((_, state)) = snapshot.devices.last_mut() {
+            state.pop();
+        }
+
+        let mut restored = VM::new();
+        let ram = restored.bus.ram().to_vec();
+        assert_eq!(
+            restored.restore(&snapshot),
+            Err("vectors : 87 bytes of state, expected 88".to_string())
+        );
+        assert_eq!(restored.read_word(devices::TIMER), Ok(0));
+        assert_eq!(restored.pending, 0);
+        assert_eq!(restored.bus.ram(), ram);
+
+        snapshot.devices = vm.bus.save();
+        snapshot.ram.pop();
+        assert!(restored.restore(&snapshot).is_err());
+        assert_eq!(restored.read_word(devices::TIMER), Ok(0));
+    }
+
+    /// Program of NOPs with an `IRET` handler at 0x80 for the timer, which
+    /// fires every 2 instructions, and SP = 0x200
+    fn timer(sr: uvm) -> VM {
+        let mut vm = boot(&vec![encode(opc!(NOP), 0, 0, true); 4]);
+        vm.write_ram(0x80, &encode(opc!(IRET), 0, 0, true)).unwrap();
+        poke(&mut vm, devices::VECTORS, 0x80);
+        poke(&mut vm, devices::TIMER, 2);
+        vm.regs.sp = 0x200;
+        vm.regs.sr = sr;
+        vm
+    }
+
+    #[test]
+    fn timer_interrupt() {
+        let sr = SR_INTERRUPTS | 1 << SR_LINES;
+        let mut vm = timer(sr);
+        step(&mut vm).unwrap();
+        assert_eq!(vm.pc(), 3);
+
+        step(&mut vm).unwrap();
+        assert_eq!(vm.pc(), 0x80);
+        assert_eq!(vm.regs.sr & SR_INTERRUPTS, 0);
+        assert_eq!(vm.regs.sp, 0x210);
+        assert_eq!(vm.read_word(0x200), Ok(6));
+        assert_eq!(vm.read_word(0x208), Ok(sr));
+
+        step(&mut vm).unwrap();
+        assert_eq!(vm.pc(), 6);
+        assert_eq!(vm.regs.sr, sr);
+        assert_eq!(vm.regs.sp, 0x200);
+    }
+
+    #[test]
+    fn masked_interrupt() {
+        let mut vm = timer(SR_INTERRUPTS);
+        step(&mut vm).unwrap();
+        step(&mut vm).unwrap();
+        assert_eq!(vm.pc(), 6);
+
+        vm.regs.sr = SR_INTERRUPTS | 1 << SR_LINES;
+        step(&mut vm).unwrap();
+        assert_eq!(vm.pc(), 0x80);
+        assert_eq!(vm.read_word(0x200), Ok(9));
+    }
+
+    /// Loads a single instruction with a trap handler at 0x80 and SP = 0x200,
+    /// then executes it
+    fn trap(opc: u8, reg: u8, val: uvm) -> (VM, Result<Option<uvm>, String>) {
+        let mut vm = boot(&[encode(opc, reg, val, false)]);
+        poke(&mut vm, devices::TRAP_HANDLER, 0x80);
+        vm.regs.sp = 0x200;
+        let result = step(&mut vm);
+        (vm, result)
+    }
+
+    #[test]
+    fn trap_division_by_zero() {
+        let (mut vm, result) = trap(opc!(DIV), R0, 0);
+        assert_eq!(result, Ok(None));
+        assert_eq!(vm.pc(), 0x80);
+        assert_eq!(vm.read_word(devices::TRAP_CAUSE), Ok(5));
+        assert_eq!(vm.read_word(devices::TRAP_VALUE), Ok(0));
+        assert_eq!(vm.read_word(0x200), Ok(0));
+    }
+
+    #[test]
+    fn trap_system_call() {
+        let (mut vm, result) = trap(opc!(SYCALL), 0, 42);
+        assert_eq!(result, Ok(None));
+        assert_eq!(vm.pc(), 0x80);
+        assert_eq!(vm.read_word(devices::TRAP_CAUSE), Ok(6));
+        assert_eq!(vm.read_word(devices::TRAP_VALUE), Ok(42));
+        assert_eq!(vm.read_word(0x200), Ok(10));
+    }
+
+    #[test]
+    fn no_trap_handler() {
+        let (mut vm, result) = execute(opc!(DIV), R0, 0, false);
+        assert!(result.unwrap_err().starts_with("DIVISION BY ZERO"));
+        assert_eq!(vm.pc(), 0);
+        assert_eq!(vm.read_word(devices::TRAP_CAUSE), Ok(0));
+
+        let (vm, result) = execute(opc!(SYCALL), 0, 42, false);
+        assert!(result
+            .unwrap_err()
+            .starts_with("SYCALL 42 without a trap handler"));
+        assert_eq!(vm.pc(), 0);
+    }
+
+    #[test]
+    fn end_of_program() {
+        let mut vm = boot(&[]);
+        vm.regs.pc = RAM_LEN as uvm;
+        assert_eq!(vm.step(), Ok(Some(Outcome::Stopped)));
+
+        poke(&mut vm, devices::TRAP_HANDLER, 0x80);
+        vm.regs.sp = 0x200;
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.pc(), 0x80);
+        assert_eq!(vm.read_word(devices::TRAP_CAUSE), Ok(1));
+        assert_eq!(vm.read_word(0x200), Ok(RAM_LEN as uvm));
+    }
+
+    /// Loads a single instruction in user mode, with the window on the first
+    /// 0x100 bytes, SP = 0xC0 and the supervisor stack at 0x300, then executes
+    /// it, with an `IRET` trap handler at 0x80 if `handler`
+    fn user(instruction: Vec<u8>, handler: bool) -> (VM, Result<Option<uvm>, String>) {
+        let mut vm = boot(&[instruction]);
+        if handler {
+            vm.write_ram(0x80, &encode(opc!(IRET), 0, 0, true)).unwrap();
+            poke(&mut vm, devices::TRAP_HANDLER, 0x80);
+        }
+        poke(&mut vm, devices::MMU + REG_LEN as uvm, 0x100);
+        poke(&mut vm, devices::STACK, 0x300);
+        vm.regs.sp = 0xC0;
+        vm.regs.sr = SR_USER;
+        let result = step(&mut vm);
+        (vm, result)
+    }
+
+    #[test]
+    fn privilege_violations() {
+        let violations = [
+            encode(opc!(HALT), 0, 0, false),
+            encode(opc!(IRET), 0, 0, true),
+            encode(opc!(SET), reg_index!(sr), 0, false),
+            encode(opc!(LOAD), R0, 0x100, false),
+            encode(opc!(LOAD), R0, devices::TIMER, false),
+        ];
+        for instruction in violations {
+            let (mut vm, result) = user(instruction.clone(), true);
+            assert_eq!(result, Ok(None));
+            assert_eq!(vm.pc(), 0x80);
+            assert_eq!(vm.regs.sr & SR_USER, 0);
+            assert_eq!(vm.regs.sp, 0x310);
+            assert_eq!(vm.read_word(devices::TRAP_CAUSE), Ok(7));
+            assert_eq!(vm.read_word(devices::STACK), Ok(0xC0));
+            assert_eq!(vm.read_word(0x300), Ok(0));
+
+            let (_, result) = user(instruction, false);
+            assert!(result.unwrap_err().starts_with("PRIVILEGE VIOLATION"));
+        }
+    }
+
+    #[test]
+    fn code_out_of_window() {
+        let mut vm = boot(&[encode(opc!(NOP), 0, 0, true)]);
+        poke(&mut vm, devices::MMU, 0x10);
+        vm.regs.sr = SR_USER;
+        assert!(step(&mut vm)
+            .unwrap_err()
+            .starts_with("PRIVILEGE VIOLATION at 0x0"));
+    }
+
+    #[test]
+    fn supervisor_stack() {
+        let (mut vm, result) = user(encode(opc!(SYCALL), 0, 1, false), true);
+        assert_eq!(result, Ok(None));
+        assert_eq!(vm.regs.sp, 0x310);
+        assert_eq!(vm.read_word(0x300), Ok(10));
+
+        step(&mut vm).unwrap();
+        assert_eq!(vm.pc(), 10);
+        assert_eq!(vm.regs.sr, SR_USER);
+        assert_eq!(vm.regs.sp, 0xC0);
+        assert_eq!(vm.read_word(devices::STACK), Ok(0x300));
+    }
+}